@@ -0,0 +1,192 @@
+//! Buddy allocator for physically contiguous frames
+// Author: Moritz Doll
+// License: GPLv3
+
+/// Largest supported order, a block of this order contains 2^10 frames = 4 MB
+pub const MAX_ORDER: usize = 10;
+
+/// Number of bitmap entries a buddy allocator for num_frames frames needs.
+/// Each order needs half the bits of the previous one, plus at most one word of rounding per order
+pub const fn buddy_entries(num_frames: usize) -> usize {
+    2 * num_frames / 32 + MAX_ORDER + 1
+}
+
+/// Tracks the free blocks of every order.
+/// There is one bitmap per order, a set bit marks a free block of 2^order frames.
+/// A block is only ever marked free in exactly one order.
+pub struct BuddyAllocator<S> {
+    table: S,
+    num_frames: usize,
+}
+
+impl<S> BuddyAllocator<S> {
+    /// Create an allocator without any free blocks.
+    /// The storage has to be zeroed and hold at least buddy_entries(num_frames) entries.
+    pub const fn new(table: S, num_frames: usize) -> Self {
+        BuddyAllocator { table, num_frames }
+    }
+}
+
+impl<S: AsRef<[u32]> + AsMut<[u32]>> BuddyAllocator<S> {
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// Change the number of managed frames, only valid while no block is free
+    pub fn set_num_frames(&mut self, num_frames: usize) -> bool {
+        if buddy_entries(num_frames) > self.table.as_ref().len() {
+            return false;
+        }
+        self.num_frames = num_frames;
+        true
+    }
+
+    /// Number of blocks of the given order
+    fn num_blocks(&self, order: usize) -> usize {
+        self.num_frames >> order
+    }
+
+    /// Number of bitmap entries used by the given order
+    fn num_entries(&self, order: usize) -> usize {
+        (self.num_blocks(order) + 31) / 32
+    }
+
+    /// Index of the first bitmap entry of the given order
+    fn first_entry(&self, order: usize) -> usize {
+        (0..order).map(|order| self.num_entries(order)).sum()
+    }
+
+    fn entries(&self, order: usize) -> &[u32] {
+        let first = self.first_entry(order);
+        &self.table.as_ref()[first..first + self.num_entries(order)]
+    }
+
+    fn is_free(&self, order: usize, block: usize) -> bool {
+        self.entries(order)[block / 32] & (1 << (block % 32)) != 0
+    }
+
+    fn set_free(&mut self, order: usize, block: usize, free: bool) {
+        let entry = self.first_entry(order) + block / 32;
+        let table = self.table.as_mut();
+        if free {
+            table[entry] |= 1 << (block % 32);
+        } else {
+            table[entry] &= !(1 << (block % 32));
+        }
+    }
+
+    /// First free block of the given order inside the frames first..end
+    fn find_free(&self, order: usize, first: usize, end: usize) -> Option<usize> {
+        let entries = self.entries(order);
+        let first_block = (first + (1 << order) - 1) >> order;
+        let end_block = (end >> order).min(self.num_blocks(order));
+        let mut block = first_block;
+        while block < end_block {
+            let entry = entries[block / 32] >> (block % 32);
+            if entry == 0 {
+                block = (block / 32 + 1) * 32;
+                continue;
+            }
+            block += entry.trailing_zeros() as usize;
+            return if block < end_block { Some(block) } else { None };
+        }
+        None
+    }
+
+    /// Number of free blocks of exactly the given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.entries(order).iter().map(|entry| entry.count_ones() as usize).sum()
+    }
+
+    /// Allocate a block of 2^order frames and return its first frame number.
+    /// Larger blocks are split if there is no free block of the requested order.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        self.alloc_in(order, 0, self.num_frames)
+    }
+
+    /// Allocate a block of 2^order frames that lies inside the frames first..end.
+    /// Both bounds should be aligned to 2^MAX_ORDER frames so that no free block crosses them.
+    pub fn alloc_in(&mut self, order: usize, first: usize, end: usize) -> Option<usize> {
+        let mut current = order;
+        let mut block = loop {
+            if current > MAX_ORDER {
+                return None;
+            }
+            if let Some(block) = self.find_free(current, first, end) {
+                break block;
+            }
+            current += 1;
+        };
+        self.set_free(current, block, false);
+        // Split the block, the upper halves stay free
+        while current > order {
+            current -= 1;
+            block *= 2;
+            self.set_free(current, block + 1, true);
+        }
+        Some(block << order)
+    }
+
+    /// Return a block of 2^order frames starting at frame.
+    /// The block is merged with its buddy as long as the buddy is free as well.
+    pub fn free(&mut self, frame: usize, order: usize) {
+        let mut order = order;
+        let mut block = frame >> order;
+        while order < MAX_ORDER {
+            let buddy = block ^ 1;
+            if buddy >= self.num_blocks(order) || !self.is_free(order, buddy) {
+                break;
+            }
+            self.set_free(order, buddy, false);
+            block /= 2;
+            order += 1;
+        }
+        self.set_free(order, block, true);
+    }
+
+    /// Remove a single frame from the free blocks.
+    /// The free block containing the frame is split, returns false if the frame is not free.
+    pub fn take(&mut self, frame: usize) -> bool {
+        for order in 0..=MAX_ORDER {
+            let block = frame >> order;
+            if block >= self.num_blocks(order) || !self.is_free(order, block) {
+                continue;
+            }
+            self.set_free(order, block, false);
+            // Split down to the frame, the halves not containing it stay free
+            let mut current = order;
+            while current > 0 {
+                current -= 1;
+                let half = frame >> current;
+                self.set_free(current, half ^ 1, true);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Return an arbitrary range of frames.
+    /// The range is split into the largest naturally aligned blocks.
+    pub fn free_range(&mut self, first: usize, count: usize) {
+        let end = first + count;
+        let mut frame = first;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.free(frame, order);
+            frame += 1 << order;
+        }
+    }
+}
+
+/// Smallest order that holds count frames
+pub fn order_for(count: usize) -> Option<usize> {
+    let order = count.next_power_of_two().trailing_zeros() as usize;
+    if order > MAX_ORDER {
+        None
+    } else {
+        Some(order)
+    }
+}
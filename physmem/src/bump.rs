@@ -0,0 +1,40 @@
+//! Bump allocator for memory that is needed before the frame allocator is running
+// Author: Moritz Doll
+// License: GPLv3
+
+/// Hands out memory from start upwards, nothing is ever freed
+pub struct BumpAllocator {
+    start: u32,
+    next: u32,
+    end: u32,
+}
+
+impl BumpAllocator {
+    /// Allocator for the physical addresses start..end
+    pub const fn new(start: u32, end: u32) -> Self {
+        BumpAllocator { start, next: start, end }
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// End of the memory handed out so far
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+
+    /// Allocate size bytes aligned to align bytes, align has to be a power of two
+    pub fn alloc(&mut self, size: u32, align: u32) -> Option<u32> {
+        if !align.is_power_of_two() {
+            return None;
+        }
+        let address = self.next.checked_add(align - 1)? & !(align - 1);
+        let next = address.checked_add(size)?;
+        if next > self.end {
+            return None;
+        }
+        self.next = next;
+        Some(address)
+    }
+}
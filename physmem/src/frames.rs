@@ -0,0 +1,189 @@
+//! Per frame metadata
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+
+pub const NUM_OWNERS: usize = 7;
+
+/// Who a frame belongs to
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Owner {
+    Free,
+    Kernel,
+    Reserved,
+    PageTable,
+    Heap,
+    User,
+    Dma,
+}
+
+impl Owner {
+    /// All owners, ordered by their index
+    pub const ALL: [Owner; NUM_OWNERS] = [Owner::Free, Owner::Kernel, Owner::Reserved, Owner::PageTable, Owner::Heap, Owner::User, Owner::Dma];
+
+    /// Position in Owner::ALL
+    pub fn index(self) -> usize {
+        self.bits() as usize
+    }
+
+    fn from_bits(bits: u32) -> Owner {
+        match bits {
+            1 => Owner::Kernel,
+            2 => Owner::Reserved,
+            3 => Owner::PageTable,
+            4 => Owner::Heap,
+            5 => Owner::User,
+            6 => Owner::Dma,
+            _ => Owner::Free,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        match self {
+            Owner::Free => 0,
+            Owner::Kernel => 1,
+            Owner::Reserved => 2,
+            Owner::PageTable => 3,
+            Owner::Heap => 4,
+            Owner::User => 5,
+            Owner::Dma => 6,
+        }
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Owner::Free => "free",
+            Owner::Kernel => "kernel",
+            Owner::Reserved => "reserved",
+            Owner::PageTable => "page table",
+            Owner::Heap => "heap",
+            Owner::User => "user",
+            Owner::Dma => "dma",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Attributes for physical frames
+/// Bits 0-3: owner, bit 4: accessed, bit 5: dirty, bit 6: poisoned, bits 16-31: reference count
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct FrameAttributes(u32);
+
+impl FrameAttributes {
+    const OWNER_MASK: u32 = 0xf;
+    const ACCESSED: u32 = 1 << 4;
+    const DIRTY: u32 = 1 << 5;
+    const POISONED: u32 = 1 << 6;
+    const REFCOUNT_SHIFT: u32 = 16;
+
+    pub const fn new_inactive() -> FrameAttributes {
+        FrameAttributes(0b0)
+    }
+
+    /// An allocated frame with a single reference
+    pub fn new_active(owner: Owner) -> FrameAttributes {
+        FrameAttributes(owner.bits() | (1 << Self::REFCOUNT_SHIFT))
+    }
+
+    pub fn new_kernel() -> FrameAttributes {
+        FrameAttributes::new_active(Owner::Kernel)
+    }
+
+    pub fn owner(self) -> Owner {
+        Owner::from_bits(self.0 & Self::OWNER_MASK)
+    }
+
+    pub fn refcount(self) -> u16 {
+        (self.0 >> Self::REFCOUNT_SHIFT) as u16
+    }
+
+    pub fn accessed(self) -> bool {
+        self.0 & Self::ACCESSED != 0
+    }
+
+    pub fn dirty(self) -> bool {
+        self.0 & Self::DIRTY != 0
+    }
+
+    /// The frame was filled with a pattern when it was freed
+    pub fn poisoned(self) -> bool {
+        self.0 & Self::POISONED != 0
+    }
+
+    fn with_refcount(self, refcount: u16) -> FrameAttributes {
+        FrameAttributes((self.0 & 0xffff) | ((refcount as u32) << Self::REFCOUNT_SHIFT))
+    }
+
+    fn with_flag(self, flag: u32, set: bool) -> FrameAttributes {
+        if set {
+            FrameAttributes(self.0 | flag)
+        } else {
+            FrameAttributes(self.0 & !flag)
+        }
+    }
+}
+
+/// Attributes of every frame, indexed by frame number
+pub struct FrameDatabase<S> {
+    frames: S,
+}
+
+impl<S> FrameDatabase<S> {
+    /// The storage has to hold one zeroed entry per frame
+    pub const fn new(frames: S) -> Self {
+        FrameDatabase { frames }
+    }
+}
+
+impl<S: AsRef<[FrameAttributes]> + AsMut<[FrameAttributes]>> FrameDatabase<S> {
+    /// Number of frames the storage can hold
+    pub fn capacity(&self) -> usize {
+        self.frames.as_ref().len()
+    }
+
+    pub fn get(&self, frame: usize) -> FrameAttributes {
+        self.frames.as_ref()[frame]
+    }
+
+    /// Set the attributes of count frames.
+    /// The poisoned bit is kept, it describes the content of the frame and not its state.
+    pub fn set(&mut self, first: usize, count: usize, attributes: FrameAttributes) {
+        for entry in self.frames.as_mut()[first..first + count].iter_mut() {
+            *entry = FrameAttributes((attributes.0 & !FrameAttributes::POISONED) | (entry.0 & FrameAttributes::POISONED));
+        }
+    }
+
+    /// Add a reference to an allocated frame, returns the new count
+    pub fn inc_ref(&mut self, frame: usize) -> u16 {
+        let entry = &mut self.frames.as_mut()[frame];
+        let refcount = entry.refcount().saturating_add(1);
+        *entry = entry.with_refcount(refcount);
+        refcount
+    }
+
+    /// Drop a reference to an allocated frame, returns the remaining count
+    pub fn dec_ref(&mut self, frame: usize) -> u16 {
+        let entry = &mut self.frames.as_mut()[frame];
+        let refcount = entry.refcount().saturating_sub(1);
+        *entry = entry.with_refcount(refcount);
+        refcount
+    }
+
+    pub fn set_accessed(&mut self, frame: usize, accessed: bool) {
+        let entry = &mut self.frames.as_mut()[frame];
+        *entry = entry.with_flag(FrameAttributes::ACCESSED, accessed);
+    }
+
+    pub fn set_dirty(&mut self, frame: usize, dirty: bool) {
+        let entry = &mut self.frames.as_mut()[frame];
+        *entry = entry.with_flag(FrameAttributes::DIRTY, dirty);
+    }
+
+    pub fn set_poisoned(&mut self, frame: usize, poisoned: bool) {
+        let entry = &mut self.frames.as_mut()[frame];
+        *entry = entry.with_flag(FrameAttributes::POISONED, poisoned);
+    }
+}
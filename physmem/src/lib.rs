@@ -0,0 +1,40 @@
+//! Bookkeeping of physical memory
+//! Nothing in here touches the hardware, so everything can be tested on the host.
+// Author: Moritz Doll
+// License: GPLv3
+
+#![no_std]
+// The kernel toolchain predates div_ceil and is_multiple_of
+#![allow(clippy::manual_div_ceil, clippy::manual_is_multiple_of)]
+
+pub mod buddy;
+pub mod bump;
+pub mod frames;
+pub mod range;
+pub mod reserved;
+mod map;
+mod stats;
+mod zone;
+
+pub use map::*;
+pub use stats::MemoryStats;
+pub use zone::Zone;
+
+/// Size of a frame in bytes
+pub const FRAME_SIZE: u32 = 4096;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Error {
+    OutOfMemory,
+    InvalidSize,
+    NotAligned,
+    NotInRange,
+    KernelFrame,
+    Reserved,
+    Overlap,
+    TooManyRegions,
+    /// Contains the frame number of the first frame that was not allocated
+    DoubleFree(usize),
+}
+
+pub type Result<T> = ::core::result::Result<T,Error>;
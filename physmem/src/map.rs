@@ -0,0 +1,347 @@
+//! Bitmap of used frames
+// Author: Moritz Doll
+// License: GPLv3
+
+use crate::buddy::{self, BuddyAllocator};
+use crate::frames::{FrameAttributes, FrameDatabase, Owner};
+use crate::reserved::{Region, ReservedRegions};
+use crate::{Error, MemoryStats, Result, Zone, FRAME_SIZE};
+
+/// Number of bitmap entries for num_frames frames
+pub const fn bitmap_entries(num_frames: usize) -> usize {
+    (num_frames + 31) / 32
+}
+
+/// Struct that tracks the availibility of physical memory
+/// Each entry contains a bitmap of 32 pages = 128 Kb memory
+/// Free memory is handed out by a buddy allocator, the bitmap records which frames are in use
+pub struct PhysicalMemoryMap<T, B, F> {
+    table: T,
+    memory_start: u32,
+    num_frames: usize,
+    kernel_frames: usize,
+    dma_start: usize,
+    reserved: ReservedRegions,
+    buddy: BuddyAllocator<B>,
+    frames: FrameDatabase<F>,
+}
+
+impl<T, B, F> PhysicalMemoryMap<T, B, F> {
+    /// Create a map of num_frames frames starting at the physical address memory_start.
+    /// All storages have to be zeroed, the bitmap needs bitmap_entries(num_frames), the buddy
+    /// allocator buddy_entries(num_frames) and the frame database num_frames entries.
+    pub const fn new(table: T, buddy_table: B, frames: F, memory_start: u32, num_frames: usize) -> Self {
+        PhysicalMemoryMap {
+            table,
+            memory_start,
+            num_frames,
+            kernel_frames: 0,
+            dma_start: num_frames,
+            reserved: ReservedRegions::new(),
+            buddy: BuddyAllocator::new(buddy_table, num_frames),
+            frames: FrameDatabase::new(frames),
+        }
+    }
+}
+
+impl<T, B, F> PhysicalMemoryMap<T, B, F>
+    where T: AsRef<[u32]> + AsMut<[u32]>,
+          B: AsRef<[u32]> + AsMut<[u32]>,
+          F: AsRef<[FrameAttributes]> + AsMut<[FrameAttributes]>
+{
+    pub fn get_entry(&self, entry: usize) -> u32 {
+        self.table.as_ref()[entry]
+    }
+
+    pub fn memory_start(&self) -> u32 {
+        self.memory_start
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    pub fn kernel_frames(&self) -> usize {
+        self.kernel_frames
+    }
+
+    pub fn reserved(&self) -> &ReservedRegions {
+        &self.reserved
+    }
+
+    pub fn buddy(&self) -> &BuddyAllocator<B> {
+        &self.buddy
+    }
+
+    pub fn frames(&self) -> &FrameDatabase<F> {
+        &self.frames
+    }
+
+    /// Reference counts and accessed/dirty bits are maintained by the users of the frames,
+    /// allocating a frame clears them
+    pub fn frames_mut(&mut self) -> &mut FrameDatabase<F> {
+        &mut self.frames
+    }
+
+    /// Shrink or grow the map to num_frames frames, e.g. after the memory size was detected.
+    /// Has to be called before any frame is marked as used and fails if the storage is too small.
+    pub fn set_num_frames(&mut self, num_frames: usize) -> Result<()> {
+        if bitmap_entries(num_frames) > self.table.as_ref().len()
+            || num_frames > self.frames.capacity()
+            || !self.buddy.set_num_frames(num_frames) {
+            return Err(Error::InvalidSize);
+        }
+        self.num_frames = num_frames;
+        self.dma_start = num_frames;
+        Ok(())
+    }
+
+    /// Frames first..end of a zone
+    pub fn zone_range(&self, zone: Zone) -> (usize, usize) {
+        match zone {
+            Zone::Normal => (0, self.dma_start),
+            Zone::Dma => (self.dma_start, self.num_frames),
+        }
+    }
+
+    pub fn zone_of(&self, frame: usize) -> Zone {
+        if frame < self.dma_start {
+            Zone::Normal
+        } else {
+            Zone::Dma
+        }
+    }
+
+    /// Reserve at least count frames at the end of memory for the DMA zone.
+    /// The zone starts at a multiple of 2^MAX_ORDER frames so that no buddy block crosses into the
+    /// normal zone. Has to be called before init_buddy.
+    pub fn set_dma_zone(&mut self, count: usize) -> Result<()> {
+        let block = 1 << buddy::MAX_ORDER;
+        if count > self.num_frames {
+            return Err(Error::InvalidSize);
+        }
+        let dma_start = (self.num_frames - count) / block * block;
+        if dma_start < self.kernel_frames {
+            return Err(Error::OutOfMemory);
+        }
+        self.dma_start = dma_start;
+        Ok(())
+    }
+
+    pub fn frame_address(&self, frame: usize) -> u32 {
+        self.memory_start + (frame as u32) * FRAME_SIZE
+    }
+
+    /// Frame number of a physical address, the address has to be frame aligned and in the map
+    pub fn frame_number(&self, address: u32) -> Result<usize> {
+        if address % FRAME_SIZE != 0 {
+            return Err(Error::NotAligned);
+        }
+        if address < self.memory_start {
+            return Err(Error::NotInRange);
+        }
+        let frame = ((address - self.memory_start) / FRAME_SIZE) as usize;
+        if frame >= self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        Ok(frame)
+    }
+
+    pub fn is_used(&self, frame: usize) -> bool {
+        self.table.as_ref()[frame / 32] & (1 << (frame % 32)) != 0
+    }
+
+    fn mark_used(&mut self, first: usize, count: usize) {
+        let table = self.table.as_mut();
+        for frame in first..first + count {
+            table[frame / 32] |= 1 << (frame % 32);
+        }
+    }
+
+    fn mark_free(&mut self, first: usize, count: usize) {
+        let table = self.table.as_mut();
+        for frame in first..first + count {
+            table[frame / 32] &= !(1 << (frame % 32));
+        }
+    }
+
+    /// Mark the frames of a kernel with kernel_size bytes at the start of the memory as used.
+    /// These frames can never be freed.
+    pub fn alloc_kernel_frames(&mut self, kernel_size: u32) -> Result<()> {
+        let k_frames = ((kernel_size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        if k_frames > self.num_frames {
+            return Err(Error::OutOfMemory);
+        }
+        self.kernel_frames = k_frames;
+        self.mark_used(0, k_frames);
+        self.frames.set(0, k_frames, FrameAttributes::new_kernel());
+        Ok(())
+    }
+
+    /// Register count frames starting at first as reserved for name.
+    /// The frames are marked as used and are never handed out or freed, even if the buddy
+    /// allocator is already running. Kernel frames inside the region keep their owner, so they
+    /// are not counted twice.
+    pub fn reserve(&mut self, name: &'static str, first: usize, count: usize) -> Result<()> {
+        if first + count > self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        self.reserved.add(Region { name, first, count })?;
+        for frame in first..first + count {
+            if !self.is_used(frame) {
+                self.buddy.take(frame);
+            }
+        }
+        self.mark_used(first, count);
+        let kernel_end = self.kernel_frames.max(first).min(first + count);
+        self.frames.set(kernel_end, first + count - kernel_end, FrameAttributes::new_active(Owner::Reserved));
+        Ok(())
+    }
+
+    /// Take a free frame out of the allocator for good without registering a region, e.g. a
+    /// frame that failed a memory test after all region slots are used up.
+    /// The frame is owned by Owner::Reserved and can never be freed.
+    pub fn retire(&mut self, frame: usize) -> Result<()> {
+        if frame >= self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        if self.is_used(frame) {
+            return Err(Error::Overlap);
+        }
+        self.buddy.take(frame);
+        self.mark_used(frame, 1);
+        self.frames.set(frame, 1, FrameAttributes::new_active(Owner::Reserved));
+        Ok(())
+    }
+
+    /// Count used, reserved and free frames and find the largest free run
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats { total: self.num_frames, ..MemoryStats::default() };
+        let mut run = 0;
+        for frame in 0..self.num_frames {
+            if self.is_used(frame) {
+                stats.used += 1;
+                run = 0;
+            } else {
+                run += 1;
+                stats.largest_free_run = stats.largest_free_run.max(run);
+            }
+            stats.per_owner[self.frames.get(frame).owner().index()] += 1;
+        }
+        stats.reserved = stats.owned_by(Owner::Reserved);
+        stats.free = stats.total - stats.used;
+        stats
+    }
+
+    /// Hand all frames that are not marked in the bitmap to the buddy allocator.
+    /// Has to be called once after the kernel frames are allocated.
+    pub fn init_buddy(&mut self) {
+        let mut frame = 0;
+        while frame < self.num_frames {
+            if self.is_used(frame) {
+                frame += 1;
+                continue;
+            }
+            let first = frame;
+            while frame < self.num_frames && !self.is_used(frame) {
+                frame += 1;
+            }
+            self.buddy.free_range(first, frame - first);
+        }
+    }
+
+    /// Allocate count physically contiguous frames, returns the first frame number.
+    /// The run is taken from the smallest sufficient buddy block, the unused tail of the block is
+    /// given back immediately.
+    pub fn allocate(&mut self, count: usize, owner: Owner) -> Result<usize> {
+        self.allocate_in(Zone::Normal, count, owner)
+    }
+
+    /// Allocate count physically contiguous frames from the given zone
+    pub fn allocate_in(&mut self, zone: Zone, count: usize, owner: Owner) -> Result<usize> {
+        if count == 0 {
+            return Err(Error::InvalidSize);
+        }
+        let order = buddy::order_for(count).ok_or(Error::InvalidSize)?;
+        self.allocate_block(zone, order, count, owner)
+    }
+
+    /// Take a block of the given order from the zone and keep its first count frames
+    fn allocate_block(&mut self, zone: Zone, order: usize, count: usize, owner: Owner) -> Result<usize> {
+        let (zone_start, zone_end) = self.zone_range(zone);
+        let first = self.buddy.alloc_in(order, zone_start, zone_end).ok_or(Error::OutOfMemory)?;
+        self.buddy.free_range(first + count, (1 << order) - count);
+        self.mark_used(first, count);
+        self.frames.set(first, count, FrameAttributes::new_active(owner));
+        Ok(first)
+    }
+
+    /// Allocate size bytes aligned to align bytes, returns the first frame number.
+    /// Buddy blocks are naturally aligned, so the block is chosen large enough for both.
+    /// Alignments below a frame are always fulfilled.
+    pub fn allocate_aligned(&mut self, size: u32, align: u32, owner: Owner) -> Result<usize> {
+        self.allocate_aligned_in(Zone::Normal, size, align, owner)
+    }
+
+    /// Allocate size bytes aligned to align bytes from the given zone
+    pub fn allocate_aligned_in(&mut self, zone: Zone, size: u32, align: u32, owner: Owner) -> Result<usize> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(Error::InvalidSize);
+        }
+        if self.memory_start % align.max(FRAME_SIZE) != 0 {
+            return Err(Error::NotAligned);
+        }
+        let count = ((size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let align_frames = (align / FRAME_SIZE).max(1) as usize;
+        let order = buddy::order_for(count.max(align_frames)).ok_or(Error::InvalidSize)?;
+        self.allocate_block(zone, order, count, owner)
+    }
+
+    /// Return count frames starting at first to the allocator.
+    /// Nothing is freed if one of the frames belongs to the kernel or is not allocated.
+    pub fn free(&mut self, first: usize, count: usize) -> Result<()> {
+        if count == 0 || first + count > self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        if first < self.kernel_frames {
+            return Err(Error::KernelFrame);
+        }
+        if (first..first + count).any(|frame| self.reserved.contains(frame) || self.frames.get(frame).owner() == Owner::Reserved) {
+            return Err(Error::Reserved);
+        }
+        if let Some(frame) = (first..first + count).find(|frame| !self.is_used(*frame)) {
+            return Err(Error::DoubleFree(frame));
+        }
+        self.mark_free(first, count);
+        self.frames.set(first, count, FrameAttributes::new_inactive());
+        self.buddy.free_range(first, count);
+        Ok(())
+    }
+
+    /// Add a reference to an allocated frame that is shared, returns the new count
+    pub fn share(&mut self, frame: usize) -> Result<u16> {
+        if frame >= self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        if !self.is_used(frame) {
+            return Err(Error::DoubleFree(frame));
+        }
+        Ok(self.frames.inc_ref(frame))
+    }
+
+    /// Drop a reference to an allocated frame, returns the remaining count.
+    /// The frame is freed together with its last reference.
+    pub fn release(&mut self, frame: usize) -> Result<u16> {
+        if frame >= self.num_frames {
+            return Err(Error::NotInRange);
+        }
+        if !self.is_used(frame) {
+            return Err(Error::DoubleFree(frame));
+        }
+        if self.frames.get(frame).refcount() > 1 {
+            Ok(self.frames.dec_ref(frame))
+        } else {
+            self.free(frame, 1).map(|_| 0)
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Allocator for ranges of an address window, used for kernel virtual memory
+// Author: Moritz Doll
+// License: GPLv3
+
+use crate::{Error, Result};
+
+/// Maximal number of ranges that can be handed out of one window
+pub const MAX_RANGES: usize = 64;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Range {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Range {
+    pub fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    fn overlaps(&self, start: u32, end: u32) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+/// Hands out non-overlapping ranges of the addresses start..end.
+/// The window must not reach the end of the 32 bit address space.
+pub struct RangeAllocator {
+    start: u32,
+    end: u32,
+    ranges: [Option<Range>; MAX_RANGES],
+}
+
+impl RangeAllocator {
+    pub const fn new(start: u32, size: u32) -> Self {
+        RangeAllocator { start, end: start + size, ranges: [None; MAX_RANGES] }
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// Find a free range of size bytes aligned to align, the lowest one is taken
+    pub fn alloc(&mut self, size: u32, align: u32) -> Result<u32> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(Error::InvalidSize);
+        }
+        let mut start = align_up(self.start, align).ok_or(Error::OutOfMemory)?;
+        loop {
+            let end = match start.checked_add(size) {
+                Some(end) if end <= self.end => end,
+                _ => return Err(Error::OutOfMemory),
+            };
+            match self.iter().find(|range| range.overlaps(start, end)) {
+                None => break,
+                Some(range) => start = align_up(range.end(), align).ok_or(Error::OutOfMemory)?,
+            }
+        }
+        self.insert(Range { start, size })?;
+        Ok(start)
+    }
+
+    /// Take the fixed range of size bytes at start, e.g. for addresses required by the hardware
+    pub fn reserve(&mut self, start: u32, size: u32) -> Result<()> {
+        if size == 0 {
+            return Err(Error::InvalidSize);
+        }
+        let end = start.checked_add(size).ok_or(Error::NotInRange)?;
+        if start < self.start || end > self.end {
+            return Err(Error::NotInRange);
+        }
+        if self.iter().any(|range| range.overlaps(start, end)) {
+            return Err(Error::Overlap);
+        }
+        self.insert(Range { start, size })
+    }
+
+    /// Give back the range starting at start, returns its size
+    pub fn free(&mut self, start: u32) -> Result<u32> {
+        for slot in self.ranges.iter_mut() {
+            if let Some(range) = *slot {
+                if range.start == start {
+                    *slot = None;
+                    return Ok(range.size);
+                }
+            }
+        }
+        Err(Error::NotInRange)
+    }
+
+    /// The range containing address
+    pub fn find(&self, address: u32) -> Option<Range> {
+        self.iter().find(|range| range.contains(address)).copied()
+    }
+
+    /// Iterate over the handed out ranges in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Range> {
+        self.ranges.iter().filter_map(|slot| slot.as_ref())
+    }
+
+    fn insert(&mut self, range: Range) -> Result<()> {
+        match self.ranges.iter_mut().find(|slot| slot.is_none()) {
+            None => Err(Error::TooManyRegions),
+            Some(slot) => {
+                *slot = Some(range);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn align_up(address: u32, align: u32) -> Option<u32> {
+    address.checked_add(align - 1).map(|address| address & !(align - 1))
+}
@@ -0,0 +1,80 @@
+//! Registry of reserved physical memory regions
+// Author: Moritz Doll
+// License: GPLv3
+
+use crate::{Error, Result};
+
+/// Maximal number of reserved regions
+pub const MAX_RESERVED_REGIONS: usize = 16;
+
+/// Frames that are used by boot code or fixed hardware structures and are never allocated
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub first: usize,
+    pub count: usize,
+}
+
+impl Region {
+    pub fn end(&self) -> usize {
+        self.first + self.count
+    }
+
+    pub fn contains(&self, frame: usize) -> bool {
+        self.first <= frame && frame < self.end()
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.first < other.end() && other.first < self.end()
+    }
+}
+
+pub struct ReservedRegions {
+    regions: [Option<Region>; MAX_RESERVED_REGIONS],
+}
+
+impl ReservedRegions {
+    pub const fn new() -> Self {
+        ReservedRegions { regions: [None; MAX_RESERVED_REGIONS] }
+    }
+
+    /// Add a region, regions must not overlap.
+    /// A region that borders on a region with the same name extends it instead of taking a slot.
+    pub fn add(&mut self, region: Region) -> Result<()> {
+        if region.count == 0 {
+            return Err(Error::InvalidSize);
+        }
+        if self.iter().any(|other| other.overlaps(&region)) {
+            return Err(Error::Overlap);
+        }
+        let adjacent = self.regions.iter_mut().filter_map(|slot| slot.as_mut())
+            .find(|other| other.name == region.name && (other.end() == region.first || region.end() == other.first));
+        if let Some(other) = adjacent {
+            other.first = other.first.min(region.first);
+            other.count += region.count;
+            return Ok(());
+        }
+        match self.regions.iter_mut().find(|slot| slot.is_none()) {
+            None => Err(Error::TooManyRegions),
+            Some(slot) => {
+                *slot = Some(region);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn contains(&self, frame: usize) -> bool {
+        self.iter().any(|region| region.contains(frame))
+    }
+
+    /// Iterate over the regions in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter_map(|slot| slot.as_ref())
+    }
+}
+
+impl Default for ReservedRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,28 @@
+//! Memory usage statistics
+// Author: Moritz Doll
+// License: GPLv3
+
+use crate::frames::{Owner, NUM_OWNERS};
+
+/// Snapshot of the frame usage, all numbers are in frames
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct MemoryStats {
+    pub total: usize,
+    /// Allocated frames including kernel and reserved frames
+    pub used: usize,
+    /// Frames in reserved regions and retired frames, kernel frames inside a region are only
+    /// counted as kernel
+    pub reserved: usize,
+    pub free: usize,
+    /// Longest run of free frames, allocations of this size may still fail if the run is not
+    /// aligned
+    pub largest_free_run: usize,
+    /// Frames per owner, indexed by Owner::index
+    pub per_owner: [usize; NUM_OWNERS],
+}
+
+impl MemoryStats {
+    pub fn owned_by(&self, owner: Owner) -> usize {
+        self.per_owner[owner.index()]
+    }
+}
@@ -0,0 +1,25 @@
+//! Physical memory zones
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+
+/// Zones split the frames into pools with different guarantees.
+/// Allocations never fall back to another zone.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Zone {
+    /// Everything that is not in another zone
+    Normal,
+    /// Frames at the end of memory that are only mapped uncached, for buffers shared with DMA
+    /// engines
+    Dma,
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Zone::Normal => f.write_str("normal"),
+            Zone::Dma => f.write_str("dma"),
+        }
+    }
+}
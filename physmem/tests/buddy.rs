@@ -0,0 +1,69 @@
+use physmem::buddy::{self, BuddyAllocator, MAX_ORDER};
+
+const NUM_FRAMES: usize = 4096;
+
+fn allocator() -> BuddyAllocator<Vec<u32>> {
+    let mut buddy = BuddyAllocator::new(vec![0; buddy::buddy_entries(NUM_FRAMES)], NUM_FRAMES);
+    buddy.free_range(0, NUM_FRAMES);
+    buddy
+}
+
+#[test]
+fn order_for_rounds_up() {
+    assert_eq!(buddy::order_for(1), Some(0));
+    assert_eq!(buddy::order_for(3), Some(2));
+    assert_eq!(buddy::order_for(40), Some(6));
+    assert_eq!(buddy::order_for(1 << MAX_ORDER), Some(MAX_ORDER));
+    assert_eq!(buddy::order_for((1 << MAX_ORDER) + 1), None);
+}
+
+#[test]
+fn free_range_uses_largest_blocks() {
+    let buddy = allocator();
+    assert_eq!(buddy.free_blocks(MAX_ORDER), NUM_FRAMES >> MAX_ORDER);
+    for order in 0..MAX_ORDER {
+        assert_eq!(buddy.free_blocks(order), 0);
+    }
+}
+
+#[test]
+fn alloc_splits_and_free_merges() {
+    let mut buddy = allocator();
+    let first = buddy.alloc(0).unwrap();
+    assert_eq!(first, 0);
+    for order in 0..MAX_ORDER {
+        assert_eq!(buddy.free_blocks(order), 1);
+    }
+    buddy.free(first, 0);
+    assert_eq!(buddy.free_blocks(MAX_ORDER), NUM_FRAMES >> MAX_ORDER);
+}
+
+#[test]
+fn alloc_is_aligned() {
+    let mut buddy = allocator();
+    buddy.alloc(0).unwrap();
+    let block = buddy.alloc(5).unwrap();
+    assert_eq!(block % 32, 0);
+}
+
+#[test]
+fn out_of_memory() {
+    let mut buddy = allocator();
+    for _ in 0..(NUM_FRAMES >> MAX_ORDER) {
+        buddy.alloc(MAX_ORDER).unwrap();
+    }
+    assert_eq!(buddy.alloc(0), None);
+}
+
+#[test]
+fn odd_number_of_frames() {
+    let num_frames = 1000;
+    let mut buddy = BuddyAllocator::new(vec![0; buddy::buddy_entries(num_frames)], num_frames);
+    buddy.free_range(0, num_frames);
+    let mut count = 0;
+    while let Some(frame) = buddy.alloc(0) {
+        assert!(frame < num_frames);
+        count += 1;
+    }
+    assert_eq!(count, num_frames);
+}
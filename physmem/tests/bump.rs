@@ -0,0 +1,13 @@
+use physmem::bump::BumpAllocator;
+
+#[test]
+fn allocations_are_aligned_and_bounded() {
+    let mut bump = BumpAllocator::new(0x8001_2004, 0x8001_6000);
+    assert_eq!(bump.alloc(0x10, 4), Some(0x8001_2004));
+    assert_eq!(bump.alloc(0x1000, 0x1000), Some(0x8001_3000));
+    assert_eq!(bump.next(), 0x8001_4000);
+    assert_eq!(bump.alloc(0x1000, 3), None);
+    assert_eq!(bump.alloc(0x2001, 4), None);
+    assert_eq!(bump.alloc(0x2000, 4), Some(0x8001_4000));
+    assert_eq!(bump.start(), 0x8001_2004);
+}
@@ -0,0 +1,280 @@
+use physmem::frames::{FrameAttributes, Owner};
+use physmem::{buddy, Error, PhysicalMemoryMap, Zone};
+use proptest::prelude::*;
+
+const NUM_FRAMES: usize = 2048;
+const MEMORY_START: u32 = 0x8000_0000;
+
+type Map = PhysicalMemoryMap<Vec<u32>, Vec<u32>, Vec<FrameAttributes>>;
+
+fn map(kernel_size: u32) -> Map {
+    let mut map = PhysicalMemoryMap::new(
+        vec![0; physmem::bitmap_entries(NUM_FRAMES)],
+        vec![0; buddy::buddy_entries(NUM_FRAMES)],
+        vec![FrameAttributes::default(); NUM_FRAMES],
+        MEMORY_START,
+        NUM_FRAMES,
+    );
+    map.alloc_kernel_frames(kernel_size).unwrap();
+    map.init_buddy();
+    map
+}
+
+#[test]
+fn kernel_frames_are_rounded_up() {
+    let map = map(32 * 4096 + 1);
+    assert_eq!(map.kernel_frames(), 33);
+    assert_eq!(map.get_entry(0), 0xffff_ffff);
+    assert_eq!(map.get_entry(1), 0b1);
+}
+
+#[test]
+fn allocation_skips_kernel() {
+    let mut map = map(5 * 4096);
+    let frame = map.allocate(1, Owner::User).unwrap();
+    assert!(frame >= 5);
+    assert_eq!(map.free(0, 1), Err(Error::KernelFrame));
+}
+
+#[test]
+fn allocate_more_than_32_frames() {
+    let mut map = map(64 * 4096);
+    let first = map.allocate(40, Owner::User).unwrap();
+    assert_eq!(first, 64);
+    for frame in first..first + 40 {
+        assert!(map.is_used(frame));
+    }
+    assert!(!map.is_used(first + 40));
+    // The tail of the block is available again
+    assert_eq!(map.allocate(8, Owner::User), Ok(first + 40));
+}
+
+#[test]
+fn double_free_is_detected() {
+    let mut map = map(4096);
+    let first = map.allocate(4, Owner::User).unwrap();
+    map.free(first, 4).unwrap();
+    assert_eq!(map.free(first + 1, 2), Err(Error::DoubleFree(first + 1)));
+}
+
+#[test]
+fn frame_number_checks_alignment_and_range() {
+    let map = map(4096);
+    assert_eq!(map.frame_number(MEMORY_START + 0x3000), Ok(3));
+    assert_eq!(map.frame_number(MEMORY_START + 0x3004), Err(Error::NotAligned));
+    assert_eq!(map.frame_number(MEMORY_START - 0x1000), Err(Error::NotInRange));
+    assert_eq!(map.frame_number(map.frame_address(NUM_FRAMES)), Err(Error::NotInRange));
+}
+
+proptest! {
+    #[test]
+    fn allocations_never_overlap(sizes in prop::collection::vec(1usize..100, 1..40), frees in prop::collection::vec(any::<bool>(), 40)) {
+        let mut map = map(7 * 4096);
+        let mut owner = vec![None; NUM_FRAMES];
+        let mut allocated = Vec::new();
+        for (index, (size, free)) in sizes.iter().zip(frees.iter()).enumerate() {
+            let first = match map.allocate(*size, Owner::User) {
+                Ok(first) => first,
+                Err(err) => { prop_assert_eq!(err, Error::OutOfMemory); continue; }
+            };
+            prop_assert!(first >= 7);
+            for slot in owner[first..first + size].iter_mut() {
+                prop_assert_eq!(*slot, None);
+                *slot = Some(index);
+            }
+            if *free {
+                map.free(first, *size).unwrap();
+                owner[first..first + size].iter_mut().for_each(|slot| *slot = None);
+            } else {
+                allocated.push((first, *size));
+            }
+        }
+        for (first, size) in allocated {
+            map.free(first, size).unwrap();
+        }
+        // Everything is merged again, so the same allocation succeeds as on a fresh map
+        let mut fresh = self::map(7 * 4096);
+        prop_assert_eq!(map.allocate(1 << buddy::MAX_ORDER, Owner::User).ok(), fresh.allocate(1 << buddy::MAX_ORDER, Owner::User).ok());
+    }
+}
+
+#[test]
+fn reserved_frames_are_never_handed_out() {
+    let mut map = map(4096);
+    map.reserve("test", 1, 3).unwrap();
+    assert_eq!(map.reserve("overlap", 3, 1), Err(Error::Overlap));
+    let mut frames = Vec::new();
+    while let Ok(frame) = map.allocate(1, Owner::User) {
+        assert!(!(1..4).contains(&frame));
+        frames.push(frame);
+    }
+    assert_eq!(frames.len(), NUM_FRAMES - 4);
+    assert_eq!(map.free(2, 1), Err(Error::Reserved));
+    assert_eq!(map.reserved().iter().count(), 1);
+}
+
+#[test]
+fn aligned_allocations() {
+    let mut map = map(4096);
+    // L1 translation table: 16 KB aligned to 16 KB
+    let first = map.allocate_aligned(16 * 1024, 16 * 1024, Owner::PageTable).unwrap();
+    assert_eq!(map.frame_address(first) % (16 * 1024), 0);
+    assert!(map.is_used(first + 3));
+    assert!(!map.is_used(first + 4));
+    // L2 table: 1 KB aligned to 1 KB fits in a single frame
+    let frame = map.allocate_aligned(1024, 1024, Owner::PageTable).unwrap();
+    assert!(map.is_used(frame));
+    assert_eq!(map.allocate_aligned(4096, 3000, Owner::PageTable), Err(Error::InvalidSize));
+}
+
+#[test]
+fn frame_database_tracks_owners() {
+    let mut map = map(2 * 4096);
+    map.reserve("test", 2, 1).unwrap();
+    let frame = map.allocate(1, Owner::Dma).unwrap();
+    assert_eq!(map.frames().get(0).owner(), Owner::Kernel);
+    assert_eq!(map.frames().get(2).owner(), Owner::Reserved);
+    assert_eq!(map.frames().get(frame).owner(), Owner::Dma);
+    assert_eq!(map.frames().get(frame).refcount(), 1);
+    assert_eq!(map.frames_mut().inc_ref(frame), 2);
+    map.frames_mut().set_dirty(frame, true);
+    assert!(map.frames().get(frame).dirty());
+    assert!(!map.frames().get(frame).accessed());
+    assert_eq!(map.frames_mut().dec_ref(frame), 1);
+    map.free(frame, 1).unwrap();
+    assert_eq!(map.frames().get(frame).owner(), Owner::Free);
+    // A frame is neither accessed nor dirty when it is handed out again
+    assert_eq!(map.allocate(1, Owner::User).unwrap(), frame);
+    assert!(!map.frames().get(frame).accessed());
+    assert!(!map.frames().get(frame).dirty());
+}
+
+#[test]
+fn zones_do_not_mix() {
+    let mut map = PhysicalMemoryMap::new(
+        vec![0; physmem::bitmap_entries(NUM_FRAMES)],
+        vec![0; buddy::buddy_entries(NUM_FRAMES)],
+        vec![FrameAttributes::default(); NUM_FRAMES],
+        MEMORY_START,
+        NUM_FRAMES,
+    );
+    map.alloc_kernel_frames(4096).unwrap();
+    map.set_dma_zone(100).unwrap();
+    map.init_buddy();
+    let (dma_start, dma_end) = map.zone_range(Zone::Dma);
+    assert_eq!((dma_start, dma_end), (NUM_FRAMES - 1024, NUM_FRAMES));
+    let frame = map.allocate_in(Zone::Dma, 3, Owner::Dma).unwrap();
+    assert_eq!(map.zone_of(frame), Zone::Dma);
+    while let Ok(frame) = map.allocate(1, Owner::User) {
+        assert_eq!(map.zone_of(frame), Zone::Normal);
+    }
+    assert!(map.allocate_in(Zone::Dma, 1, Owner::Dma).is_ok());
+}
+
+#[test]
+fn map_can_be_resized_to_the_detected_size() {
+    let mut map = PhysicalMemoryMap::new(
+        vec![0; physmem::bitmap_entries(NUM_FRAMES)],
+        vec![0; buddy::buddy_entries(NUM_FRAMES)],
+        vec![FrameAttributes::default(); NUM_FRAMES],
+        MEMORY_START,
+        NUM_FRAMES,
+    );
+    assert_eq!(map.set_num_frames(NUM_FRAMES + 1), Err(Error::InvalidSize));
+    map.set_num_frames(NUM_FRAMES / 2).unwrap();
+    map.alloc_kernel_frames(4096).unwrap();
+    map.init_buddy();
+    let mut count = 1;
+    while let Ok(frame) = map.allocate(1, Owner::User) {
+        assert!(frame < NUM_FRAMES / 2);
+        count += 1;
+    }
+    assert_eq!(count, NUM_FRAMES / 2);
+    assert_eq!(map.frame_number(map.frame_address(NUM_FRAMES / 2)), Err(Error::NotInRange));
+}
+
+#[test]
+fn stats_count_owners_and_free_runs() {
+    let mut map = map(10 * 4096);
+    map.reserve("test", 100, 4).unwrap();
+    map.allocate(3, Owner::Heap).unwrap();
+    let stats = map.stats();
+    assert_eq!(stats.total, NUM_FRAMES);
+    assert_eq!(stats.used, 17);
+    assert_eq!(stats.free, NUM_FRAMES - 17);
+    assert_eq!(stats.reserved, 4);
+    assert_eq!(stats.owned_by(Owner::Kernel), 10);
+    assert_eq!(stats.owned_by(Owner::Reserved), 4);
+    assert_eq!(stats.owned_by(Owner::Heap), 3);
+    assert_eq!(stats.owned_by(Owner::Free), NUM_FRAMES - 17);
+    assert_eq!(stats.largest_free_run, NUM_FRAMES - 104);
+}
+
+#[test]
+fn poisoned_bit_survives_allocation() {
+    let mut map = map(4096);
+    let frame = map.allocate(1, Owner::User).unwrap();
+    map.free(frame, 1).unwrap();
+    map.frames_mut().set_poisoned(frame, true);
+    assert_eq!(map.allocate(1, Owner::Heap), Ok(frame));
+    assert!(map.frames().get(frame).poisoned());
+    assert_eq!(map.frames().get(frame).owner(), Owner::Heap);
+}
+
+#[test]
+fn adjacent_reserved_regions_are_merged() {
+    let mut map = map(4096);
+    map.reserve("bad memory", 200, 1).unwrap();
+    map.reserve("bad memory", 201, 1).unwrap();
+    map.reserve("bad memory", 199, 1).unwrap();
+    map.reserve("other", 202, 1).unwrap();
+    let regions: Vec<_> = map.reserved().iter().map(|region| (region.name, region.first, region.count)).collect();
+    assert_eq!(regions, vec![("bad memory", 199, 3), ("other", 202, 1)]);
+    assert!(map.is_used(199) && map.is_used(201));
+    assert_eq!(map.free(199, 1), Err(Error::Reserved));
+}
+
+#[test]
+fn shared_frames_are_freed_with_the_last_reference() {
+    let mut map = map(4096);
+    let frame = map.allocate(1, Owner::User).unwrap();
+    assert_eq!(map.share(frame), Ok(2));
+    assert_eq!(map.release(frame), Ok(1));
+    assert!(map.is_used(frame));
+    assert_eq!(map.frames().get(frame).owner(), Owner::User);
+    assert_eq!(map.release(frame), Ok(0));
+    assert!(!map.is_used(frame));
+    assert_eq!(map.release(frame), Err(Error::DoubleFree(frame)));
+    assert_eq!(map.share(frame), Err(Error::DoubleFree(frame)));
+    assert_eq!(map.release(0), Err(Error::KernelFrame));
+}
+
+#[test]
+fn reserved_kernel_frames_are_counted_once() {
+    let mut map = map(10 * 4096);
+    map.reserve("boot stacks", 8, 4).unwrap();
+    let stats = map.stats();
+    assert_eq!(stats.used, 12);
+    assert_eq!(stats.reserved, 2);
+    assert_eq!(stats.owned_by(Owner::Kernel), 10);
+    assert_eq!(stats.owned_by(Owner::Reserved), 2);
+    assert_eq!(map.frames().get(9).owner(), Owner::Kernel);
+    assert_eq!(map.frames().get(10).owner(), Owner::Reserved);
+}
+
+#[test]
+fn retired_frames_are_never_handed_out() {
+    let mut map = map(4096);
+    for frame in (2..NUM_FRAMES).step_by(2) {
+        map.retire(frame).unwrap();
+    }
+    assert_eq!(map.retire(2), Err(Error::Overlap));
+    assert_eq!(map.free(2, 1), Err(Error::Reserved));
+    assert_eq!(map.stats().reserved, NUM_FRAMES / 2 - 1);
+    let mut count = 0;
+    while let Ok(frame) = map.allocate(1, Owner::Heap) {
+        assert_eq!(frame % 2, 1);
+        count += 1;
+    }
+    assert_eq!(count, NUM_FRAMES / 2);
+}
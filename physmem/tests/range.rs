@@ -0,0 +1,63 @@
+use physmem::range::{RangeAllocator, MAX_RANGES};
+use physmem::Error;
+use proptest::prelude::*;
+
+const START: u32 = 0xF000_0000;
+const SIZE: u32 = 0x10_0000;
+
+#[test]
+fn ranges_are_aligned_and_reused() {
+    let mut ranges = RangeAllocator::new(START, SIZE);
+    assert_eq!(ranges.alloc(0x1000, 0x1000), Ok(START));
+    assert_eq!(ranges.alloc(0x2000, 0x4000), Ok(START + 0x4000));
+    assert_eq!(ranges.alloc(0x1000, 0x1000), Ok(START + 0x1000));
+    assert_eq!(ranges.free(START + 0x4000), Ok(0x2000));
+    assert_eq!(ranges.free(START + 0x4000), Err(Error::NotInRange));
+    assert_eq!(ranges.alloc(0x3000, 0x1000), Ok(START + 0x2000));
+    assert_eq!(ranges.find(START + 0x4fff).map(|range| range.start), Some(START + 0x2000));
+    assert_eq!(ranges.alloc(SIZE, 0x1000), Err(Error::OutOfMemory));
+    assert_eq!(ranges.alloc(0, 0x1000), Err(Error::InvalidSize));
+}
+
+#[test]
+fn fixed_ranges_are_skipped() {
+    let mut ranges = RangeAllocator::new(START, SIZE);
+    ranges.reserve(START + 0x1000, 0x1000).unwrap();
+    assert_eq!(ranges.reserve(START, 0x2000), Err(Error::Overlap));
+    assert_eq!(ranges.reserve(START + SIZE, 0x1000), Err(Error::NotInRange));
+    assert_eq!(ranges.alloc(0x2000, 0x1000), Ok(START + 0x2000));
+    assert_eq!(ranges.alloc(0x1000, 0x1000), Ok(START));
+}
+
+#[test]
+fn number_of_ranges_is_limited() {
+    let mut ranges = RangeAllocator::new(START, SIZE);
+    for _ in 0..MAX_RANGES {
+        ranges.alloc(0x1000, 0x1000).unwrap();
+    }
+    assert_eq!(ranges.alloc(0x1000, 0x1000), Err(Error::TooManyRegions));
+}
+
+proptest! {
+    #[test]
+    fn ranges_never_overlap(sizes in prop::collection::vec(1u32..0x8000, 1..60), frees in prop::collection::vec(any::<bool>(), 60)) {
+        let mut ranges = RangeAllocator::new(START, SIZE);
+        let mut allocated: Vec<(u32, u32)> = Vec::new();
+        for (size, free) in sizes.iter().zip(frees.iter()) {
+            let start = match ranges.alloc(*size, 0x1000) {
+                Ok(start) => start,
+                Err(err) => { prop_assert_eq!(err, Error::OutOfMemory); continue; }
+            };
+            prop_assert_eq!(start % 0x1000, 0);
+            prop_assert!(start >= START && start + size <= START + SIZE);
+            for (other, other_size) in allocated.iter() {
+                prop_assert!(start + size <= *other || other + other_size <= start);
+            }
+            allocated.push((start, *size));
+            if *free {
+                let (start, size) = allocated.remove(0);
+                prop_assert_eq!(ranges.free(start), Ok(size));
+            }
+        }
+    }
+}
@@ -5,3 +5,5 @@ pub mod cpuinfo;
 pub mod memory;
 pub mod interrupts;
 pub mod allocator;
+pub mod sync;
+pub mod mmu;
@@ -5,6 +5,7 @@ use armv7::regs::vmem_control::*;
 use armv7::regs::core_regs::*;
 use armv7::regs::program_state;
 use core::fmt;
+use crate::arch::mmu;
 
 pub fn get_mmu_status() -> bool {
     //let mmu_status = SCTLR.read_as_enum(SCTLR::MMU)?;
@@ -51,6 +52,21 @@ pub fn print_status<T: fmt::Write>(serial: &mut T) -> fmt::Result {
     } else {
         writeln!(serial, "Tex Remap disabled.")?;
     }
+    if SCTLR.is_set(SCTLR::CACHE) {
+        writeln!(serial, "Data cache is on.")?;
+    } else {
+        writeln!(serial, "Data cache is off.")?;
+    }
+    if SCTLR.is_set(SCTLR::INSTR) {
+        writeln!(serial, "Instruction cache is on.")?;
+    } else {
+        writeln!(serial, "Instruction cache is off.")?;
+    }
+    if mmu::branch_prediction_enabled() {
+        writeln!(serial, "Branch prediction is on.")?;
+    } else {
+        writeln!(serial, "Branch prediction is off.")?;
+    }
     Ok(())
 }
 
@@ -1,14 +1,64 @@
 //! The interrupt handlers
 
-use armv7::structures::paging;
 use armv7::structures::interrupts;
 use armv7::regs::vmem_control::*;
 use armv7::regs::security::*;
 use core::fmt;
-use crate::driver::uart;
-use crate::kernel::kernel_info;
+use crate::driver;
 use crate::bsp::memory_map;
 use crate::arch::cpuinfo;
+use crate::arch::memory;
+use crate::arch::mmu;
+use crate::arch::memory::fault::Fault;
+
+const PREFETCH_ABORT_VECTOR: u32 = 3;
+const DATA_ABORT_VECTOR: u32 = 4;
+/// ldr pc, [pc, #24]: jump to the handler address stored 0x20 bytes after the vector
+const LDR_PC_LITERAL: u32 = 0xe59f_f018;
+
+// The aborts save the registers of the interrupted code on the abort stack and return to the
+// faulting instruction, so it is executed again once the fault is resolved.
+// The handlers are called with sp aligned to 8 bytes as the AAPCS requires, the address of the
+// saved registers is kept in r4, which the handlers preserve
+global_asm!(r#"
+.section .text
+.global data_abort_entry, prefetch_abort_entry
+
+data_abort_entry:
+    sub lr, lr, #8
+    push {r0-r12, lr}
+    mov r0, sp
+    mov r4, sp
+    bic sp, sp, #7
+    bl data_abort_handler
+    mov sp, r4
+    pop {r0-r12, lr}
+    movs pc, lr
+
+prefetch_abort_entry:
+    sub lr, lr, #4
+    push {r0-r12, lr}
+    mov r0, sp
+    mov r4, sp
+    bic sp, sp, #7
+    bl prefetch_abort_handler
+    mov sp, r4
+    pop {r0-r12, lr}
+    movs pc, lr
+"#);
+
+extern "C" {
+    fn data_abort_entry();
+    fn prefetch_abort_entry();
+}
+
+/// Registers saved by the abort entries
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r: [u32; 13],
+    /// Address of the faulting instruction
+    pub pc: u32,
+}
 
 #[no_mangle]
 pub unsafe extern "C" fn irq_rhandler() -> () {
@@ -18,27 +68,71 @@ pub unsafe extern "C" fn irq_rhandler() -> () {
 #[naked]
 pub extern "C" fn exception_handler() -> ! {
     use core::fmt::Write;
-    let mut uart0 = uart::Uart::new(memory_map::UART_BASE.as_u32());
+    let mut uart0 = driver::console();
     //uart0.flush_txfifo();
     writeln!(uart0, "CPU exception").unwrap();
     cpuinfo::print_mode(&mut uart0).ok();
     loop { }
 }
 
-pub fn init<T: fmt::Write>(serial: &mut T, base_table: &mut paging::TranslationTable, _kernel_offset_mapping: &paging::OffsetMapping) -> fmt::Result {
+#[no_mangle]
+pub extern "C" fn data_abort_handler(frame: &mut ExceptionFrame) {
+    handle_abort("Data abort", Fault::data(), frame);
+}
+
+#[no_mangle]
+pub extern "C" fn prefetch_abort_handler(frame: &mut ExceptionFrame) {
+    handle_abort("Prefetch abort", Fault::prefetch(), frame);
+}
+
+/// Return if the fault is resolved, otherwise report it and stop
+fn handle_abort(name: &str, fault: Fault, frame: &ExceptionFrame) {
+    use core::fmt::Write;
+    let err = match memory::fault::resolve(&fault) {
+        Ok(()) => return,
+        Err(err) => err,
+    };
+    let mut uart0 = driver::console();
+    writeln!(uart0, "{} at {:#010x}: {}, {:?}", name, frame.pc, fault, err).ok();
+    if let memory::MemoryError::Locked(lock) = err {
+        writeln!(uart0, "The faulting code holds {}, the fault can not be resolved", lock).ok();
+    }
+    print_registers(&mut uart0, frame).ok();
+    cpuinfo::print_mode(&mut uart0).ok();
+    loop { }
+}
+
+fn print_registers<T: fmt::Write>(serial: &mut T, frame: &ExceptionFrame) -> fmt::Result {
+    for (index, value) in frame.r.iter().enumerate() {
+        write!(serial, "r{:<2} {:#010x}", index, value)?;
+        if index % 4 == 3 {
+            writeln!(serial)?;
+        } else {
+            write!(serial, "  ")?;
+        }
+    }
+    writeln!(serial, "pc  {:#010x}", frame.pc)
+}
+
+/// Point a vector of the vector page at handler
+unsafe fn set_vector(index: u32, handler: u32) {
+    let vector = (memory_map::VECTOR_PAGE + index * 4) as *mut u32;
+    vector.write_volatile(LDR_PC_LITERAL);
+    vector.offset(8).write_volatile(handler);
+}
+
+pub fn init<T: fmt::Write>(serial: &mut T) -> fmt::Result {
     use armv7::*;
 
     writeln!(serial, "\nInitializing Interrupts.\n")?;
 
-    // Initialize mapping of pagetable: 0x8000_5000 to 0xfffx_xxxx
-    let page_table_addr = kernel_info::kernel_start() + 0x5400 as u32;
-    let page_table_addr2 = kernel_info::kernel_start() + 0x5800 as u32;
-    let vector_table_addr = memory_map::DRAM_START + 0x5000 as u32;
-    // Add a pagetable to the 0xfffx_xxxx at virtual address 0xC000_5400
-    let mut page_table_fff = unsafe { paging::PageTable::create(page_table_addr, 4095, base_table).unwrap() };
-    let mut page_table_000 = unsafe { paging::PageTable::create(page_table_addr2, 0, base_table).unwrap() };
-    page_table_fff[240] = paging::PageDescriptor::new_smallpage(vector_table_addr, 0b001, 0, false, false, false, false, false).unwrap();
-    page_table_000[0] = paging::PageDescriptor::new_smallpage(vector_table_addr, 0b001, 0, false, false, false, false, false).unwrap();
+    // The vector page is mapped to the high vector address in the fixmap, it is never freed.
+    // It is only writable until the vectors are in place and executable afterwards.
+    let vector_page = memory::virtual_address(memory_map::VECTOR_PAGE);
+    let vector_table_addr = memory::allocate_frame(memory::Owner::Kernel).unwrap().leak();
+    let attributes = memory::PageAttributes::new(memory::MemoryType::DRAM, memory::Permission::ReadWrite);
+    memory::vmem::reserve_range(memory::vmem::Window::Fixmap, memory_map::VECTOR_PAGE, memory::PAGE_SIZE).unwrap();
+    memory::KERNEL_SPACE.lock().map(vector_page, vector_table_addr, attributes).unwrap();
     writeln!(serial,"Added interrupt page for interrupt table")?;
 
     // Define the interrupt handler
@@ -49,10 +143,20 @@ pub fn init<T: fmt::Write>(serial: &mut T, base_table: &mut paging::TranslationT
     //let irq_addr = unsafe { paging::VirtualAddress::from_ptr(&irq_handler as *const u32) };
     //writeln!(serial, "Pointer to memory: {:#x}", irq_addr)?;
     vector_table.init(exc_handler);
+    // The aborts have their own handlers
+    unsafe {
+        set_vector(PREFETCH_ABORT_VECTOR, prefetch_abort_entry as usize as u32);
+        set_vector(DATA_ABORT_VECTOR, data_abort_entry as usize as u32);
+    }
+    mmu::clean_dcache_range(memory_map::VECTOR_PAGE, memory::PAGE_SIZE);
+    mmu::invalidate_icache();
+    let attributes = memory::PageAttributes::new(memory::MemoryType::DRAM, memory::Permission::ReadOnly).executable();
+    memory::KERNEL_SPACE.lock().protect(vector_page, attributes).unwrap();
     //vector_table.init(irq_addr);
     //unsafe { interrupts::init_vectortable(&mut vectors_start, &mut vectors_end, 0xffff_0000 as *mut u32); }
-    SCTLR.modify(SCTLR::EXCENDIAN::LittleEndian + SCTLR::THUMBEXC::Arm + SCTLR::VECENABLE::UseVectorTable + SCTLR::INSTR::Disabled + SCTLR::CACHE::Disabled);
-    VBAR.set(0x8000_5000);
+    SCTLR.modify(SCTLR::EXCENDIAN::LittleEndian + SCTLR::THUMBEXC::Arm + SCTLR::VECENABLE::UseVectorTable);
+    // The linear mapping is not executable
+    VBAR.set(memory_map::VECTOR_PAGE);
 
     //let exc_handler = unsafe { &except_handler as *const u32 as usize as u32 };
     //writeln!(serial, "Exception handler at {:#x}", kernel_offset_mapping.convert_phys_addr(exc_handler).unwrap())?;
@@ -4,17 +4,60 @@
 //use core::{fmt, ops::RangeInclusive};
 
 use core::ops;
+use core::mem;
+use core::fmt;
 //use core::iter;
 //use core::slice;
 use armv7::structures::paging;
-use armv7::PhysicalAddress;
+use armv7::{PhysicalAddress, VirtualAddress};
 use crate::bsp::memory_map;
 use crate::kernel::kernel_info;
+use crate::arch::sync::IrqSafeMutex;
+use crate::arch::mmu;
+
+pub mod early;
+use early::EarlySlice;
+#[cfg(feature = "poison")]
+mod poison;
+pub mod address_space;
+pub use address_space::{init_kernel_space, AddressSpace, KERNEL_SPACE, PAGE_SIZE, USER_SPACE_END};
+mod ioremap;
+pub use ioremap::{ioremap, iounmap};
+pub mod vmem;
+pub use vmem::{allocate_stack, free_stack, vfree, vmalloc};
+pub mod fault;
+pub use fault::{register_lazy_region, unregister_lazy_region};
+pub mod walk;
+pub use walk::translate;
 
 #[derive(Copy,Clone,Debug)]
 pub enum MemoryError {
     PagingError(paging::PageError),
     OutOfMemory,
+    InvalidSize,
+    NotAligned,
+    NotInRange,
+    KernelFrame,
+    Reserved,
+    Overlap,
+    TooManyRegions,
+    /// The early allocator is not running
+    Unavailable,
+    AlreadyMapped,
+    NotMapped,
+    /// The address is covered by a section and not by a second level table
+    SectionMapped,
+    /// All 255 ASIDs of user address spaces are in use
+    OutOfAsids,
+    /// An abort that is not resolved by demand paging
+    AccessViolation,
+    /// The lock with the given name is held by the code that faulted
+    Locked(&'static str),
+    /// The page at the given physical address is not backed by DRAM and can not be shared
+    NotDram(u32),
+    /// The software walk and the hardware translate an address differently, contains both
+    TranslationMismatch(Option<u32>, Option<u32>),
+    DoubleFree(PhysicalAddress),
 }
 
 pub type Result<T> = ::core::result::Result<T,MemoryError>;
@@ -22,9 +65,21 @@ pub type Result<T> = ::core::result::Result<T,MemoryError>;
 #[derive(Copy,Clone,Debug)]
 pub enum MemoryType {
     DRAM,
+    /// Normal memory that is never cached, used for the DMA zone
+    DmaCoherent,
     Device,
 }
 
+pub use physmem::Zone;
+
+/// How the frames of a zone have to be mapped
+pub fn zone_memory_type(zone: Zone) -> MemoryType {
+    match zone {
+        Zone::Normal => MemoryType::DRAM,
+        Zone::Dma => MemoryType::DmaCoherent,
+    }
+}
+
 #[derive(Copy,Clone,Debug)]
 pub enum Permission {
     ReadOnly,
@@ -42,6 +97,32 @@ pub struct PageAttributes {
     exec: bool,
 }
 
+impl PageAttributes {
+    /// Memory that is only accessible by the kernel and not executable
+    pub fn new(mem_type: MemoryType, perms: Permission) -> PageAttributes {
+        PageAttributes {
+            mem_type: mem_type,
+            perms: perms,
+            unpriv: false,
+            accessed: false,
+            dirty: false,
+            exec: false,
+        }
+    }
+
+    /// Allow instruction fetches, device memory is never executable
+    pub fn executable(mut self) -> PageAttributes {
+        self.exec = true;
+        self
+    }
+
+    /// Allow accesses from user mode
+    pub fn unprivileged(mut self) -> PageAttributes {
+        self.unpriv = true;
+        self
+    }
+}
+
 impl Default for PageAttributes {
     fn default() -> PageAttributes {
         PageAttributes {
@@ -55,155 +136,408 @@ impl Default for PageAttributes {
     }
 }
 
-/// Attributes for physical frames
+pub use physmem::frames::{FrameAttributes, Owner};
+
+/// A frame that holds four 1 KB second level tables
 #[derive(Copy,Clone,Debug)]
-pub struct FrameAttributes(u8); // Size will be reduced
+pub struct PageTableManagement {
+    address: PhysicalAddress,
+    num_free: u8,
+    used: u8, // bitmap of the used slots
+}
+
+impl PageTableManagement {
+    const SLOTS: u8 = (physmem::FRAME_SIZE / L2_TABLE_SIZE) as u8;
 
-impl FrameAttributes {
-    pub fn new_inactive() -> FrameAttributes {
-        FrameAttributes(0b0)
+    fn new(address: PhysicalAddress) -> Self {
+        PageTableManagement { address: address, num_free: Self::SLOTS, used: 0 }
     }
 
-    pub fn new_active() -> FrameAttributes {
-        FrameAttributes(0b1)
+    fn alloc(&mut self) -> Option<PhysicalAddress> {
+        if self.num_free == 0 {
+            return None;
+        }
+        let slot = (!self.used).trailing_zeros();
+        self.used |= 1 << slot;
+        self.num_free -= 1;
+        Some(self.address + slot * L2_TABLE_SIZE)
     }
 
-    pub fn new_kernel() -> FrameAttributes {
-        FrameAttributes(0b11)
+    /// Slot of a table in this frame
+    fn slot(&self, table: PhysicalAddress) -> Option<u32> {
+        let table = table.as_u32();
+        let start = self.address.as_u32();
+        if table < start || table >= start + physmem::FRAME_SIZE || table % L2_TABLE_ALIGN != 0 {
+            None
+        } else {
+            Some((table - start) / L2_TABLE_SIZE)
+        }
     }
-    // Todo: Bits for Access and Dirty
 }
 
-pub struct PageTableManagement {
+pub const MAX_PAGE_TABLE_FRAMES: usize = 256;
+
+/// Pool of second level tables.
+/// Frames are taken from PHYSICAL_MEMORY when all slots are used and given back when all their
+/// tables are freed.
+pub struct PageTablePool {
+    frames: [Option<PageTableManagement>; MAX_PAGE_TABLE_FRAMES],
+}
+
+impl PageTablePool {
+    pub const fn new() -> Self {
+        PageTablePool { frames: [None; MAX_PAGE_TABLE_FRAMES] }
+    }
+
+    /// Physical address of an unused second level table
+    pub fn allocate(&mut self) -> Result<PhysicalAddress> {
+        if let Some(table) = self.frames.iter_mut().filter_map(|frame| frame.as_mut()).find_map(|frame| frame.alloc()) {
+            return Ok(table);
+        }
+        let slot = self.frames.iter_mut().find(|frame| frame.is_none()).ok_or(MemoryError::OutOfMemory)?;
+        // Page tables are freed through the pool, not by dropping the frame
+        let address = allocate_frame(Owner::PageTable)?.leak();
+        let mut frame = PageTableManagement::new(address);
+        let table = frame.alloc();
+        *slot = Some(frame);
+        table.ok_or(MemoryError::OutOfMemory)
+    }
+
+    /// Return a second level table to the pool.
+    /// The table must not be referenced by any first level table anymore.
+    pub fn free(&mut self, table: PhysicalAddress) -> Result<()> {
+        for entry in self.frames.iter_mut() {
+            let frame = match entry {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let slot = match frame.slot(table) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if frame.used & (1 << slot) == 0 {
+                return Err(MemoryError::DoubleFree(table));
+            }
+            frame.used &= !(1 << slot);
+            frame.num_free += 1;
+            if frame.num_free == PageTableManagement::SLOTS {
+                let address = frame.address;
+                *entry = None;
+                PHYSICAL_MEMORY.lock().free_frame(address)?;
+            }
+            return Ok(());
+        }
+        Err(MemoryError::NotInRange)
+    }
+}
+
+pub static PAGE_TABLES: IrqSafeMutex<PageTablePool> = IrqSafeMutex::new(PageTablePool::new());
+
+
+
+/// A physical frame owned by the holder, it is returned to PHYSICAL_MEMORY when dropped
+#[derive(Debug)]
+pub struct Frame {
     address: PhysicalAddress,
-    num_free: u8,
 }
 
+impl Frame {
+    pub fn address(&self) -> PhysicalAddress {
+        self.address
+    }
 
+    /// Give up ownership without freeing the frame, e.g. for page tables that live forever
+    pub fn leak(self) -> PhysicalAddress {
+        let address = self.address;
+        mem::forget(self);
+        address
+    }
+}
 
-// Physical Memory Dummy allocator
-pub struct PhysicalMemory {
-    size: u32,
+impl ops::Drop for Frame {
+    fn drop(&mut self) {
+        if let Err(err) = PHYSICAL_MEMORY.lock().free_frame(self.address) {
+            panic!("Could not free frame {:#x}: {:?}", self.address, err);
+        }
+    }
+}
+
+/// Physically contiguous frames owned by the holder, they are returned to PHYSICAL_MEMORY when
+/// dropped
+#[derive(Debug)]
+pub struct FrameRange {
+    start: PhysicalAddress,
+    num_frames: u32,
 }
 
-impl PhysicalMemory {
-    pub fn new(size: u32) -> Self {
-        PhysicalMemory { size: size }
+impl FrameRange {
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.start
+    }
+
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    pub fn size(&self) -> u32 {
+        self.num_frames * 4096
+    }
+
+    /// Give up ownership without freeing the frames
+    pub fn leak(self) -> PhysicalAddress {
+        let start = self.start;
+        mem::forget(self);
+        start
     }
 }
 
-impl ops::Drop for PhysicalMemory {
+impl ops::Drop for FrameRange {
     fn drop(&mut self) {
+        if let Err(err) = PHYSICAL_MEMORY.lock().free_frames(self.start, self.num_frames) {
+            panic!("Could not free frames at {:#x}: {:?}", self.start, err);
+        }
     }
 }
 
-pub const NUM_BITMAP_ENTRIES: usize = (memory_map::DRAM_SIZE_KB / (4 * 32) ) as usize;
+/// Short-descriptor first level tables are 16 KB and have to be aligned to 16 KB
+pub const L1_TABLE_SIZE: u32 = 16 * 1024;
+pub const L1_TABLE_ALIGN: u32 = 16 * 1024;
+/// Second level tables are 1 KB and have to be aligned to 1 KB
+pub const L2_TABLE_SIZE: u32 = 1024;
+pub const L2_TABLE_ALIGN: u32 = 1024;
 
-/// Struct that tracks the availibility of physical memory
-/// Each entry contains a bitmap of 32 pages = 128 Kb memory
+/// The physical memory of the board.
+/// The bookkeeping is done by physmem::PhysicalMemoryMap, this adds the physical addresses and
+/// the kernel layout.
 pub struct PhysicalMemoryMap {
-    table: [u32; NUM_BITMAP_ENTRIES],
+    map: physmem::PhysicalMemoryMap<EarlySlice<u32>, EarlySlice<u32>, EarlySlice<FrameAttributes>>,
     memory_start: PhysicalAddress,
 }
 
 impl PhysicalMemoryMap {
-    pub unsafe fn get_entry(&mut self, entry: usize) -> u32 {
-        self.table[entry]
+    pub fn get_entry(&self, entry: usize) -> u32 {
+        self.map.get_entry(entry)
+    }
+    pub fn get_physical_address(&self, index: usize, offset: u32) -> PhysicalAddress {
+        if offset > 32 {
+            panic!("Out of bounds!");
+        }
+        self.frame_address(index * 32 + offset as usize)
+    }
+    pub fn frame_address(&self, frame: usize) -> PhysicalAddress {
+        self.memory_start + (frame as u32) * physmem::FRAME_SIZE
+    }
+
+    /// Set up the map for size bytes of DRAM and take over from the early allocator.
+    /// The bookkeeping itself is taken from the early allocator, all its allocations are marked
+    /// as kernel frames.
+    pub fn init(&mut self, dram_size: u32) -> Result<()> {
+        let num_frames = (dram_size / physmem::FRAME_SIZE) as usize;
+        let (table, buddy_table, frames) = unsafe {
+            (EarlySlice::new(physmem::bitmap_entries(num_frames))?,
+             EarlySlice::new(physmem::buddy::buddy_entries(num_frames))?,
+             EarlySlice::new(num_frames)?)
+        };
+        self.map = physmem::PhysicalMemoryMap::new(table, buddy_table, frames, self.memory_start.as_u32(), num_frames);
+        let early_end = early::finish()?;
+        self.map.alloc_kernel_frames(early_end.as_u32() - self.memory_start.as_u32()).map_err(|err| self.error(err))
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.map.num_frames()
+    }
+
+    /// Whether a frame is allocated, reserved or belongs to the kernel
+    pub fn is_used(&self, frame: usize) -> bool {
+        self.map.is_used(frame)
     }
-    /// This is the the totally unchecked free function - of course it is not safe to use.
-    /// This function should not be public
-    pub unsafe fn free_raw(&mut self, entry: usize, bits_to_free: u32) -> paging::Result<()> {
-        if entry > NUM_BITMAP_ENTRIES - 1 {
-            return Err(paging::PageError::NotInRange);
+
+    pub fn dram_size(&self) -> u32 {
+        self.map.num_frames() as u32 * physmem::FRAME_SIZE
+    }
+
+    /// Frame number of a physical address, the address has to be frame aligned and in DRAM
+    pub fn frame_number(&self, address: PhysicalAddress) -> Result<usize> {
+        self.map.frame_number(address.as_u32()).map_err(|err| self.error(err))
+    }
+
+    fn error(&self, err: physmem::Error) -> MemoryError {
+        match err {
+            physmem::Error::OutOfMemory => MemoryError::OutOfMemory,
+            physmem::Error::InvalidSize => MemoryError::InvalidSize,
+            physmem::Error::NotAligned => MemoryError::NotAligned,
+            physmem::Error::NotInRange => MemoryError::NotInRange,
+            physmem::Error::KernelFrame => MemoryError::KernelFrame,
+            physmem::Error::Reserved => MemoryError::Reserved,
+            physmem::Error::Overlap => MemoryError::Overlap,
+            physmem::Error::TooManyRegions => MemoryError::TooManyRegions,
+            physmem::Error::DoubleFree(frame) => MemoryError::DoubleFree(self.frame_address(frame)),
         }
-        // We delete all marked bits from the bitmap
-        self.table[entry] &= !bits_to_free;
+    }
+
+    /// Hand all frames that are not marked in the bitmap to the buddy allocator.
+    /// Has to be called once after the kernel frames are allocated.
+    pub fn init_buddy(&mut self) {
+        self.map.init_buddy();
+    }
+
+    /// Reserve the end of DRAM for the DMA zone, has to be called before init_buddy
+    pub fn init_dma_zone(&mut self) -> Result<()> {
+        let count = memory_map::DMA_ZONE_SIZE / physmem::FRAME_SIZE;
+        self.map.set_dma_zone(count as usize).map_err(|err| self.error(err))
+    }
+
+    /// Physical address range start..end of a zone
+    pub fn zone_range(&self, zone: Zone) -> (PhysicalAddress, PhysicalAddress) {
+        let (first, end) = self.map.zone_range(zone);
+        (self.frame_address(first), self.frame_address(end))
+    }
+
+    pub fn allocate_frames(&mut self, num_frames: u32, owner: Owner) -> Result<FrameRange> {
+        self.allocate_frames_in(Zone::Normal, num_frames, owner)
+    }
+
+    pub fn allocate_frames_in(&mut self, zone: Zone, num_frames: u32, owner: Owner) -> Result<FrameRange> {
+        let first = self.map.allocate_in(zone, num_frames as usize, owner).map_err(|err| self.error(err))?;
+        self.poison_allocated(first, num_frames as usize);
+        Ok(FrameRange { start: self.frame_address(first), num_frames: num_frames })
+    }
+
+    pub fn allocate_frame(&mut self, owner: Owner) -> Result<Frame> {
+        let frame = self.map.allocate(1, owner).map_err(|err| self.error(err))?;
+        self.poison_allocated(frame, 1);
+        Ok(Frame { address: self.frame_address(frame) })
+    }
+
+    /// Allocate size bytes of physically contiguous memory aligned to align bytes
+    pub fn allocate_aligned(&mut self, size: u32, align: u32, owner: Owner) -> Result<FrameRange> {
+        self.allocate_aligned_in(Zone::Normal, size, align, owner)
+    }
+
+    pub fn allocate_aligned_in(&mut self, zone: Zone, size: u32, align: u32, owner: Owner) -> Result<FrameRange> {
+        let first = self.map.allocate_aligned_in(zone, size, align, owner).map_err(|err| self.error(err))?;
+        let num_frames = (size + physmem::FRAME_SIZE - 1) / physmem::FRAME_SIZE;
+        self.poison_allocated(first, num_frames as usize);
+        Ok(FrameRange { start: self.frame_address(first), num_frames: num_frames })
+    }
+
+    /// Allocate a first level translation table suitable for TTBR0 or TTBR1
+    pub fn allocate_translation_table(&mut self) -> Result<FrameRange> {
+        self.allocate_aligned(L1_TABLE_SIZE, L1_TABLE_ALIGN, Owner::PageTable)
+    }
+
+    /// Attributes of the frame containing address, e.g. to find out who owns it
+    pub fn frame_info(&self, address: PhysicalAddress) -> Result<FrameAttributes> {
+        let frame_start = address.as_u32() & !(physmem::FRAME_SIZE - 1);
+        let frame = self.map.frame_number(frame_start).map_err(|err| self.error(err))?;
+        Ok(self.map.frames().get(frame))
+    }
+
+    /// Return num_frames frames starting at address to the allocator.
+    /// Nothing is freed if one of the frames belongs to the kernel or is not allocated.
+    pub fn free_frames(&mut self, address: PhysicalAddress, num_frames: u32) -> Result<()> {
+        let first = self.frame_number(address)?;
+        self.map.free(first, num_frames as usize).map_err(|err| self.error(err))?;
+        self.poison_freed(first, num_frames as usize);
         Ok(())
     }
-    pub unsafe fn alloc_raw(&mut self, entry: usize, bits_to_alloc: u32) -> paging::Result<()> {
-        if entry > NUM_BITMAP_ENTRIES - 1 {
-            return Err(paging::PageError::NotInRange);
+
+    pub fn free_frame(&mut self, address: PhysicalAddress) -> Result<()> {
+        self.free_frames(address, 1)
+    }
+
+    /// Record an access to the frame at address, writes also make it dirty.
+    /// The MMU does not track accesses, the fault handlers and unmap call this instead.
+    pub fn record_access(&mut self, address: PhysicalAddress, write: bool) -> Result<()> {
+        let frame = self.frame_number(address)?;
+        let frames = self.map.frames_mut();
+        frames.set_accessed(frame, true);
+        if write {
+            frames.set_dirty(frame, true);
         }
-        // We add all marked bits to the bitmap
-        self.table[entry] |= bits_to_alloc;
         Ok(())
     }
-    unsafe fn find_empty_entry(&mut self) -> Result<(usize, u32)> {
-        let base_ptr = self.table.as_ptr();
-        let mut iter = self.table.iter_mut().filter(|num| is_empty(**num));
-        match iter.next() {
-            None => Err(MemoryError::OutOfMemory),
-            Some(bitmap_ptr) => {
-                *bitmap_ptr = 0xffff_ffff;
-                let ptr_c = bitmap_ptr as *const u32;
-                let index = ptr_c.offset_from(base_ptr) as usize;
-                Ok((index, *bitmap_ptr))
-            }
+
+    /// Add a reference to the frame at address that is mapped once more, returns the new count
+    pub fn share_frame(&mut self, address: PhysicalAddress) -> Result<u16> {
+        let frame = self.frame_number(address)?;
+        self.map.share(frame).map_err(|err| self.error(err))
+    }
+
+    /// Drop a reference to the frame at address, it is freed with the last one.
+    /// Returns the remaining count.
+    pub fn release_frame(&mut self, address: PhysicalAddress) -> Result<u16> {
+        let frame = self.frame_number(address)?;
+        let refcount = self.map.release(frame).map_err(|err| self.error(err))?;
+        if refcount == 0 {
+            self.poison_freed(frame, 1);
         }
+        Ok(refcount)
     }
-    /// Try to allocate num_pages. Returns the index of the bitmap and the bitmask of the allocated
-    /// pages
-    pub unsafe fn find_entries(&mut self, num_pages: u32) -> Result<(usize, u32)> {
-        let base_ptr = self.table.as_ptr();
-        if num_pages > 32 {
-            self.find_empty_entry()
-        } else {
-            let mut iter = self.table.iter_mut().filter(|num| is_not_full(**num) && (num.count_zeros() > num_pages));
-            let reference = match iter.next() {
-                None => return Err(MemoryError::OutOfMemory),
-                Some(refu32) => refu32,
-            };
-            // Mark the correct bits
-            let bitmask = match calculate_bitmask(*reference, num_pages) {
-                None => return Err(MemoryError::OutOfMemory),
-                Some(bits) => bits,
-            };
-            // remove the correct bits from the reference
-            *reference |= bitmask;
-            let ref_u32 = reference as *const u32;
-            let index = ref_u32.offset_from(base_ptr) as usize;
-            Ok((index,bitmask))
+
+    /// Verify that freed frames were not written since they were freed and fill them with
+    /// poison::ALLOC_PATTERN. Corrupted frames are reported on the serial console.
+    #[cfg(feature = "poison")]
+    fn poison_allocated(&mut self, first: usize, count: usize) {
+        for frame in first..first + count {
+            let address = self.frame_address(frame);
+            if self.map.frames().get(frame).poisoned() {
+                if let Some(offset) = unsafe { poison::check(address, physmem::FRAME_SIZE, poison::FREE_PATTERN) } {
+                    poison::report(address, offset);
+                }
+            }
+            unsafe { poison::fill(address, physmem::FRAME_SIZE, poison::ALLOC_PATTERN) };
+            self.map.frames_mut().set_poisoned(frame, false);
         }
     }
-    
-    pub fn get_physical_address(&self, index: usize, offset: u32) -> PhysicalAddress {
-        if offset > 32 {
-            panic!("Out of bounds!");
+
+    #[cfg(not(feature = "poison"))]
+    fn poison_allocated(&mut self, _first: usize, _count: usize) {}
+
+    /// Fill freed frames with poison::FREE_PATTERN
+    #[cfg(feature = "poison")]
+    fn poison_freed(&mut self, first: usize, count: usize) {
+        for frame in first..first + count {
+            unsafe { poison::fill(self.frame_address(frame), physmem::FRAME_SIZE, poison::FREE_PATTERN) };
+            self.map.frames_mut().set_poisoned(frame, true);
         }
-        let offset_from_base = ((index as u32) * 32 + offset) * 4;
-        self.memory_start + (offset_from_base * 1024)
     }
-    pub fn allocate_frame(&mut self) -> Result<PhysicalAddress> {
-        let (index, bitmask) = unsafe { self.find_entries(1) }?;
-        let offset = bitmask.trailing_zeros();
-        Ok(self.get_physical_address(index, offset))
+
+    #[cfg(not(feature = "poison"))]
+    fn poison_freed(&mut self, _first: usize, _count: usize) {}
+
+    /// Register size bytes at start as reserved for name.
+    /// Reserved frames are never handed out by the allocator and can not be freed.
+    pub fn reserve_region(&mut self, name: &'static str, start: PhysicalAddress, size: u32) -> Result<()> {
+        let first = self.frame_number(start)?;
+        let count = (size + physmem::FRAME_SIZE - 1) / physmem::FRAME_SIZE;
+        self.map.reserve(name, first, count as usize).map_err(|err| self.error(err))
     }
 
-    fn _index(&self, item: &u32) -> Option<usize> {
-        let index = (item as *const _ as usize - self.table.as_ptr() as usize) / 4;
-        if NUM_BITMAP_ENTRIES > index {
-            Some(index)
-        } else {
-            None
-        }
+    /// Take the free frame at address out of the allocator for good without using a region slot
+    pub fn retire_frame(&mut self, address: PhysicalAddress) -> Result<()> {
+        let frame = self.frame_number(address)?;
+        self.map.retire(frame).map_err(|err| self.error(err))
     }
 
-    pub unsafe fn alloc_kernel_frames(&mut self) -> Result<()> {
-        let k_size = kernel_info::kernel_memory_size() as u32;
-        let mut k_frames = k_size / 4096;
-        let mut iter = self.table.iter_mut();
-        while k_frames > 0 {
-            let reference = match iter.next() {
-                None => return Err(MemoryError::OutOfMemory),
-                Some(refu32) => refu32,
-            };
-            if k_frames > 32 {
-                *reference = 0xffff_ffff;
-                k_frames -= 32;
-            } else {
-                *reference = left_shift_ones(k_frames);
-                return Ok(())
-            }
+    /// Register the kernel image and the memory set up by init.s
+    pub fn reserve_boot_regions(&mut self) -> Result<()> {
+        let image_offset = (kernel_info::kernel_memory_size() - kernel_info::kernel_size()) as u32;
+        self.reserve_region("kernel image", memory_map::DRAM_START + image_offset, kernel_info::kernel_size() as u32)?;
+        self.reserve_region("boot translation table", memory_map::DRAM_START + memory_map::BOOT_TRANSLATION_TABLE_OFFSET, memory_map::BOOT_TRANSLATION_TABLE_SIZE)?;
+        self.reserve_region("boot stacks", memory_map::DRAM_START + memory_map::BOOT_STACKS_OFFSET, memory_map::BOOT_STACKS_SIZE)
+    }
+
+    pub fn stats(&self) -> physmem::MemoryStats {
+        self.map.stats()
+    }
+
+    pub fn print_reserved_regions<T: fmt::Write>(&self, serial: &mut T) -> fmt::Result {
+        writeln!(serial, "Reserved physical memory:")?;
+        for region in self.map.reserved().iter() {
+            let start = self.map.frame_address(region.first);
+            let end = self.map.frame_address(region.end()) - 1;
+            writeln!(serial, "    {:#010x} - {:#010x}: {}", start, end, region.name)?;
         }
         Ok(())
     }
@@ -211,42 +545,96 @@ impl PhysicalMemoryMap {
 
 pub fn kernel_frames() -> u32 {
     let k_size = kernel_info::kernel_memory_size() as u32;
-    k_size / 4096
+    (k_size + physmem::FRAME_SIZE - 1) / physmem::FRAME_SIZE
 }
 
-fn calculate_bitmask(bitmap: u32, num: u32) -> Option<u32> {
-    if num > bitmap.count_zeros() {
-        return None
-    }
-    for x in 0..32 {
-        let shifted = bitmap << x;
-        if shifted.count_zeros() - x == num {
-            return Some( (!shifted) >> x)
+// Initialized statically, the storage is added by PhysicalMemoryMap::init
+pub static PHYSICAL_MEMORY: IrqSafeMutex<PhysicalMemoryMap> = IrqSafeMutex::new(PhysicalMemoryMap {
+    map: physmem::PhysicalMemoryMap::new(EarlySlice::empty(), EarlySlice::empty(), EarlySlice::empty(), memory_map::DRAM_START.as_u32(), 0),
+    memory_start: memory_map::DRAM_START,
+});
+
+/// Address of a DRAM address in the linear mapping at KERNEL_VIRTUAL_START
+pub fn linear_address(address: PhysicalAddress) -> u32 {
+    address.as_u32() - memory_map::DRAM_START.as_u32() + memory_map::KERNEL_VIRTUAL_START
+}
+
+/// A virtual address from its numeric value
+pub fn virtual_address(address: u32) -> VirtualAddress {
+    unsafe { VirtualAddress::from_ptr(address as *const u8) }
+}
+
+/// Make the section mapping of DRAM at kernel_start() set up by init.s cover exactly size bytes.
+/// Sections beyond the installed memory are removed, missing ones are added.
+pub unsafe fn resize_linear_map(size: u32) {
+    const SECTION_SIZE: u32 = 0x10_0000;
+    // AP = 01, Section, Shareable like in init.s
+    const SECTION_FLAGS: u32 = 0x400 | 0x2 | (1 << 16);
+    let base_table = (kernel_info::kernel_start() + memory_map::BOOT_TRANSLATION_TABLE_OFFSET).as_u32() as *mut u32;
+    let first_index = memory_map::KERNEL_VIRTUAL_START / SECTION_SIZE;
+    let max_sections = memory_map::MAX_DRAM_SIZE / SECTION_SIZE;
+    let sections = (size + SECTION_SIZE - 1) / SECTION_SIZE;
+    for section in 0..max_sections {
+        let entry = base_table.offset((first_index + section) as isize);
+        if section < sections {
+            *entry = (memory_map::DRAM_START.as_u32() + section * SECTION_SIZE) | SECTION_FLAGS;
+        } else {
+            *entry = 0;
         }
     }
-    None
+    mmu::invalidate_tlb();
+}
+
+// Safe interface to PHYSICAL_MEMORY
+
+pub fn allocate_frame(owner: Owner) -> Result<Frame> {
+    PHYSICAL_MEMORY.lock().allocate_frame(owner)
 }
 
-fn is_empty(num: u32) -> bool {
-    num == 0
+pub fn allocate_frames(num_frames: u32, owner: Owner) -> Result<FrameRange> {
+    PHYSICAL_MEMORY.lock().allocate_frames(num_frames, owner)
 }
-fn is_not_full(num: u32) -> bool {
-    !(num == 0xffff_ffff)
+
+pub fn allocate_frames_in(zone: Zone, num_frames: u32, owner: Owner) -> Result<FrameRange> {
+    PHYSICAL_MEMORY.lock().allocate_frames_in(zone, num_frames, owner)
 }
 
+pub fn allocate_aligned(size: u32, align: u32, owner: Owner) -> Result<FrameRange> {
+    PHYSICAL_MEMORY.lock().allocate_aligned(size, align, owner)
+}
 
-// for simplicity, we first implement the function that marks the first pages as not-available (1)
-// given the size of the kernel space, we calculate how many u32's we have set to full
-// (0xffff_ffff) and how we have to color the final u32
-fn left_shift_ones(shift: u32) -> u32 {
-    !(!0 << shift)
+pub fn allocate_aligned_in(zone: Zone, size: u32, align: u32, owner: Owner) -> Result<FrameRange> {
+    PHYSICAL_MEMORY.lock().allocate_aligned_in(zone, size, align, owner)
 }
 
-fn right_shift_ones(shift: u32) -> u32 {
-    !(!0 >> shift)
+pub fn allocate_translation_table() -> Result<FrameRange> {
+    PHYSICAL_MEMORY.lock().allocate_translation_table()
 }
 
+pub fn reserve_region(name: &'static str, start: PhysicalAddress, size: u32) -> Result<()> {
+    PHYSICAL_MEMORY.lock().reserve_region(name, start, size)
+}
+
+pub fn frame_info(address: PhysicalAddress) -> Result<FrameAttributes> {
+    PHYSICAL_MEMORY.lock().frame_info(address)
+}
 
-pub static mut PHYSICAL_MEMORY: PhysicalMemoryMap = PhysicalMemoryMap { table: [0; NUM_BITMAP_ENTRIES], memory_start: memory_map::DRAM_START };
+pub fn meminfo() -> physmem::MemoryStats {
+    PHYSICAL_MEMORY.lock().stats()
+}
+
+pub fn print_meminfo<T: fmt::Write>(serial: &mut T) -> fmt::Result {
+    let stats = meminfo();
+    let kb = |frames: usize| frames as u32 * physmem::FRAME_SIZE / 1024;
+    writeln!(serial, "Total memory: {} KB", kb(stats.total))?;
+    writeln!(serial, "Used memory: {} KB", kb(stats.used))?;
+    writeln!(serial, "Reserved memory: {} KB", kb(stats.reserved))?;
+    writeln!(serial, "Free memory: {} KB", kb(stats.free))?;
+    writeln!(serial, "Largest free block: {} KB", kb(stats.largest_free_run))?;
+    for owner in Owner::ALL.iter().filter(|owner| **owner != Owner::Free) {
+        writeln!(serial, "    {}: {} KB", owner, kb(stats.owned_by(*owner)))?;
+    }
+    Ok(())
+}
 
 // Virtual Memory Dummy allocator
@@ -0,0 +1,581 @@
+//! Address spaces built from short-descriptor translation tables
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use armv7::{PhysicalAddress, VirtualAddress};
+use crate::arch::mmu;
+use crate::arch::sync::IrqSafeMutex;
+use crate::bsp::memory_map;
+use crate::kernel::kernel_info;
+use super::{linear_address, zone_memory_type, MemoryError, MemoryType, Owner, PageAttributes, Permission, Result, Zone};
+use super::{L1_TABLE_SIZE, L2_TABLE_SIZE, PAGE_TABLES, PHYSICAL_MEMORY};
+
+/// TTBR0 translates the addresses below 2^(32 - N) for user space, TTBR1 everything above for
+/// the kernel. With short descriptors the split can only be at 2 GB, 1 GB, 512 MB and so on, so
+/// the kernel at 0xC000_0000 can not get the upper 1 GB alone. N = 1 puts the split at 2 GB and
+/// 0x8000_0000 - 0xBFFF_FFFF is translated by TTBR1 but never used by the kernel.
+const TTBCR_N: u32 = 1;
+/// Disables table walks through TTBR0 while no user address space is active
+const TTBCR_PD0: u32 = 1 << 4;
+/// First address translated by the kernel address space
+pub const USER_SPACE_END: u32 = 1 << (32 - TTBCR_N);
+/// First level tables of user address spaces only cover USER_SPACE_END
+const USER_TABLE_SIZE: u32 = L1_TABLE_SIZE >> TTBCR_N;
+
+pub const PAGE_SIZE: u32 = 4096;
+const PAGE_SHIFT: u32 = 12;
+const SECTION_SHIFT: u32 = 20;
+const SECTION_SIZE: u32 = 1 << SECTION_SHIFT;
+const L2_ENTRIES: u32 = 256;
+
+// First level descriptors
+const L1_TYPE_MASK: u32 = 0b11;
+const L1_FAULT: u32 = 0b00;
+const L1_PAGE_TABLE: u32 = 0b01;
+const L1_SECTION: u32 = 0b10;
+const L1_TABLE_BASE_MASK: u32 = !(L2_TABLE_SIZE - 1);
+
+// Second level small page descriptors
+const SMALL_PAGE: u32 = 0b10;
+const NG: u32 = 1 << 11;
+const XN: u32 = 1 << 0;
+const B: u32 = 1 << 2;
+const C: u32 = 1 << 3;
+const AP_SHIFT: u32 = 4;
+const AP2: u32 = 1 << 9;
+const S: u32 = 1 << 10;
+const PAGE_BASE_MASK: u32 = !(PAGE_SIZE - 1);
+/// TEX[1] is free for the OS with TEX remap, it marks read-only pages with a shared frame that are
+/// copied before they can become writable
+const COPY_ON_WRITE: u32 = 1 << 7;
+/// TEX[2] is free as well, it marks copy-on-write pages that are writable once they are copied
+const WRITE_AFTER_COPY: u32 = 1 << 8;
+
+/// Write-back write-allocate table walks, the same as init.s uses
+const TTBR_FLAGS: u32 = 0x48;
+
+// With TEX remap TEX[0], C and B select one of eight memory regions:
+// 0: strongly-ordered, 1 (B): device, 2 (C): normal non-cacheable, 3 (C, B): normal write-back
+// write-allocate. Devices and normal memory with the S bit set are shareable.
+const PRRR: u32 = (0b01 << 2) | (0b10 << 4) | (0b10 << 6) | (1 << 16) | (1 << 17) | (1 << 19);
+const NMRR: u32 = (0b01 << 6) | (0b01 << 22);
+
+/// Small page descriptor for the frame at phys
+fn small_page(phys: u32, attributes: PageAttributes) -> u32 {
+    // Regions of PRRR and NMRR, TEX is left zero
+    let memory = match attributes.mem_type {
+        MemoryType::DRAM => C | B | S,
+        MemoryType::DmaCoherent => C | S,
+        MemoryType::Device => B,
+    };
+    // AP[2] disables writes, AP[1] allows user mode access, AP[0] is always set
+    let access = match (attributes.perms, attributes.unpriv) {
+        (Permission::ReadWrite, false) => 0b01 << AP_SHIFT,
+        (Permission::ReadWrite, true) => 0b11 << AP_SHIFT,
+        (Permission::ReadOnly, false) => AP2 | (0b01 << AP_SHIFT),
+        (Permission::ReadOnly, true) => AP2 | (0b11 << AP_SHIFT),
+    };
+    let execute_never = match attributes.mem_type {
+        MemoryType::Device => XN,
+        _ if attributes.exec => 0,
+        _ => XN,
+    };
+    phys | SMALL_PAGE | memory | access | execute_never
+}
+
+/// Section descriptor for the 1 MB at phys.
+/// The fields are the same as for small pages, only at different positions.
+fn section(phys: PhysicalAddress, attributes: PageAttributes) -> u32 {
+    let page = small_page(phys.as_u32(), attributes) & !PAGE_BASE_MASK;
+    phys.as_u32() | L1_SECTION
+        | (page & (B | C))
+        | ((page & XN) << 4)
+        | (((page >> AP_SHIFT) & 0b11) << 10)
+        | ((page & (AP2 | S)) << 6)
+}
+
+/// Write a translation table entry and make it visible to the table walk, which does not look
+/// into the L1 data cache of the Cortex-A8
+unsafe fn write_entry(entry: *mut u32, descriptor: u32) {
+    entry.write_volatile(descriptor);
+    mmu::clean_dcache_line(entry as u32);
+}
+
+/// Zero a new translation table
+unsafe fn zero_table(table: PhysicalAddress, size: u32) {
+    ptr::write_bytes(linear_address(table) as *mut u8, 0, size as usize);
+    mmu::clean_dcache_range(linear_address(table), size);
+}
+
+/// Bitmap of the used ASIDs, ASID 0 belongs to the kernel
+static ASIDS: IrqSafeMutex<[u32; 8]> = IrqSafeMutex::new([1, 0, 0, 0, 0, 0, 0, 0]);
+
+fn allocate_asid() -> Result<u8> {
+    let mut asids = ASIDS.lock();
+    let (index, entry) = asids.iter_mut().enumerate()
+        .find(|(_, entry)| **entry != !0)
+        .ok_or(MemoryError::OutOfAsids)?;
+    let bit = (!*entry).trailing_zeros();
+    *entry |= 1 << bit;
+    Ok((index as u32 * 32 + bit) as u8)
+}
+
+fn free_asid(asid: u8) {
+    ASIDS.lock()[asid as usize / 32] &= !(1 << (asid % 32));
+}
+
+/// First level table and ASID of the user address space that was switched to last, 0 if there is
+/// none. The tables are 8 KB aligned, so the ASID fits into the lower bits.
+static ACTIVE_USER_SPACE: AtomicU32 = AtomicU32::new(0);
+
+/// ASID of the active user address space, 0 if there is none
+pub fn active_asid() -> u8 {
+    ACTIVE_USER_SPACE.load(Ordering::Relaxed) as u8
+}
+
+/// Run f on the address space that translates virt, i.e. the kernel address space or the active
+/// user address space. Used by the abort handlers, so KERNEL_SPACE is not waited for.
+pub(super) fn with_address_space<F, T>(virt: u32, f: F) -> Result<T>
+    where F: FnOnce(&mut AddressSpace) -> Result<T>
+{
+    if virt >= USER_SPACE_END {
+        let mut kernel_space = KERNEL_SPACE.try_lock().ok_or(MemoryError::Locked("KERNEL_SPACE"))?;
+        return f(&mut kernel_space);
+    }
+    let active = ACTIVE_USER_SPACE.load(Ordering::Relaxed);
+    if active == 0 {
+        return Err(MemoryError::NotMapped);
+    }
+    // The owner of the active address space keeps its tables alive, the copy must not free them
+    let mut space = mem::ManuallyDrop::new(AddressSpace { table: active & !0xff, asid: active as u8 });
+    f(&mut space)
+}
+
+/// The frame at phys, None if phys is not in DRAM, e.g. for pages of device registers
+fn dram_frame(phys: u32) -> Option<PhysicalAddress> {
+    if phys < memory_map::DRAM_START.as_u32() {
+        return None;
+    }
+    let frame = physical(phys);
+    PHYSICAL_MEMORY.lock().frame_number(frame).ok().map(|_| frame)
+}
+
+/// Physical address of a translation table entry, all tables live in DRAM
+fn physical(address: u32) -> PhysicalAddress {
+    memory_map::DRAM_START + (address - memory_map::DRAM_START.as_u32())
+}
+
+/// A first level translation table and the second level tables it references.
+/// Pages are mapped at 4 KB granularity, second level tables are taken from PAGE_TABLES when
+/// needed and given back when their last page is unmapped.
+/// The kernel address space is used through TTBR1 and its mappings are global. User address
+/// spaces are used through TTBR0, cover the addresses below USER_SPACE_END and their mappings
+/// are tagged with their ASID.
+pub struct AddressSpace {
+    /// Physical address of the first level table
+    table: u32,
+    /// 0 for the kernel
+    asid: u8,
+}
+
+impl AddressSpace {
+    /// Kernel address space of an existing first level table in DRAM.
+    /// The table has to stay valid as long as the address space is used.
+    pub unsafe fn new(table: PhysicalAddress) -> Self {
+        AddressSpace { table: table.as_u32(), asid: 0 }
+    }
+
+    /// A kernel address space without any mappings, the first level table is never freed
+    pub fn create() -> Result<Self> {
+        let table = super::allocate_translation_table()?.leak();
+        unsafe { zero_table(table, L1_TABLE_SIZE) };
+        Ok(AddressSpace { table: table.as_u32(), asid: 0 })
+    }
+
+    /// A user address space with its own ASID and without any mappings.
+    /// The mapped frames are released and the tables and the ASID are freed when it is dropped.
+    pub fn create_user() -> Result<Self> {
+        let asid = allocate_asid()?;
+        let table = match super::allocate_aligned(USER_TABLE_SIZE, USER_TABLE_SIZE, Owner::PageTable) {
+            Ok(table) => table.leak(),
+            Err(err) => {
+                free_asid(asid);
+                return Err(err);
+            }
+        };
+        unsafe { zero_table(table, USER_TABLE_SIZE) };
+        Ok(AddressSpace { table: table.as_u32(), asid: asid })
+    }
+
+    pub fn asid(&self) -> u8 {
+        self.asid
+    }
+
+    fn is_user(&self) -> bool {
+        self.asid != 0
+    }
+
+    /// Addresses of user address spaces have to be below USER_SPACE_END
+    fn check_range(&self, virt: u32) -> Result<()> {
+        if self.is_user() && virt >= USER_SPACE_END {
+            Err(MemoryError::NotInRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Bits added to every descriptor of this address space
+    fn descriptor_flags(&self) -> u32 {
+        if self.is_user() { NG } else { 0 }
+    }
+
+    pub fn table_address(&self) -> PhysicalAddress {
+        physical(self.table)
+    }
+
+    fn l1_entry(&self, virt: u32) -> *mut u32 {
+        (linear_address(self.table_address()) as *mut u32).wrapping_offset((virt >> SECTION_SHIFT) as isize)
+    }
+
+    /// Second level entry of virt, a missing second level table is created if create is set
+    fn l2_entry(&mut self, virt: u32, create: bool) -> Result<*mut u32> {
+        self.check_range(virt)?;
+        let l1 = self.l1_entry(virt);
+        let mut descriptor = unsafe { l1.read_volatile() };
+        match descriptor & L1_TYPE_MASK {
+            L1_PAGE_TABLE => {},
+            L1_FAULT if create => {
+                let table = PAGE_TABLES.lock().allocate()?;
+                unsafe {
+                    zero_table(table, L2_TABLE_SIZE);
+                    descriptor = table.as_u32() | L1_PAGE_TABLE;
+                    write_entry(l1, descriptor);
+                }
+            },
+            L1_FAULT => return Err(MemoryError::NotMapped),
+            _ => return Err(MemoryError::SectionMapped),
+        }
+        let table = linear_address(physical(descriptor & L1_TABLE_BASE_MASK)) as *mut u32;
+        Ok(table.wrapping_offset(((virt >> PAGE_SHIFT) % L2_ENTRIES) as isize))
+    }
+
+    /// Map the page at virt to the frame at phys
+    pub fn map(&mut self, virt: VirtualAddress, phys: PhysicalAddress, attributes: PageAttributes) -> Result<()> {
+        self.map_address(virt.as_u32(), phys.as_u32(), attributes)
+    }
+
+    /// Map the page at virt to phys, which does not have to be in DRAM
+    pub(super) fn map_address(&mut self, virt: u32, phys: u32, attributes: PageAttributes) -> Result<()> {
+        if virt % PAGE_SIZE != 0 || phys % PAGE_SIZE != 0 {
+            return Err(MemoryError::NotAligned);
+        }
+        let entry = self.l2_entry(virt, true)?;
+        unsafe {
+            if entry.read_volatile() != 0 {
+                return Err(MemoryError::AlreadyMapped);
+            }
+            write_entry(entry, small_page(phys, attributes) | self.descriptor_flags());
+        }
+        mmu::invalidate_tlb_entry(virt, self.asid);
+        Ok(())
+    }
+
+    /// Map size bytes at virt to the physically contiguous memory at phys
+    pub fn map_range(&mut self, virt: VirtualAddress, phys: PhysicalAddress, size: u32, attributes: PageAttributes) -> Result<()> {
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            self.map(virt + offset, phys + offset, attributes)?;
+        }
+        Ok(())
+    }
+
+    /// Map size bytes at virt to phys with 1 MB sections, everything has to be section aligned
+    pub fn map_sections(&mut self, virt: VirtualAddress, phys: PhysicalAddress, size: u32, attributes: PageAttributes) -> Result<()> {
+        if virt.as_u32() % SECTION_SIZE != 0 || phys.as_u32() % SECTION_SIZE != 0 || size % SECTION_SIZE != 0 {
+            return Err(MemoryError::NotAligned);
+        }
+        for offset in (0..size).step_by(SECTION_SIZE as usize) {
+            self.check_range(virt.as_u32() + offset)?;
+            let entry = self.l1_entry(virt.as_u32() + offset);
+            unsafe {
+                if entry.read_volatile() != 0 {
+                    return Err(MemoryError::AlreadyMapped);
+                }
+                write_entry(entry, section(phys + offset, attributes) | (self.descriptor_flags() << 6));
+            }
+        }
+        mmu::invalidate_tlb();
+        Ok(())
+    }
+
+    /// Take over the first level entry of virt from another address space
+    fn share_l1_entry(&mut self, other: &AddressSpace, virt: u32) {
+        unsafe { write_entry(self.l1_entry(virt), other.l1_entry(virt).read_volatile()) };
+    }
+
+    /// Use this address space for the user part of the address space, only one user address
+    /// space is active at a time. The TLB entries of other address spaces stay valid.
+    pub unsafe fn switch_to(&self) {
+        assert!(self.is_user(), "The kernel address space is always active");
+        mmu::switch_ttbr0(self.table | TTBR_FLAGS, self.asid, TTBCR_N);
+        ACTIVE_USER_SPACE.store(self.table | self.asid as u32, Ordering::Relaxed);
+    }
+
+    /// A new user address space that shares all pages with this one. All pages become
+    /// copy-on-write in both address spaces, writable pages are copied on the first write by
+    /// either of them.
+    /// Every shared frame gets another reference and the lazy regions are copied. Pages outside
+    /// of DRAM can not be shared and fail with NotDram.
+    pub fn fork(&mut self) -> Result<AddressSpace> {
+        assert!(self.is_user(), "Only user address spaces can be forked");
+        let mut child = AddressSpace::create_user()?;
+        // Dropping the child on errors releases its lazy regions and shared frames again
+        super::fault::copy_lazy_regions(self.asid, child.asid)?;
+        let shared = self.share_pages(&mut child);
+        // Writable pages may have become read-only even if not all pages could be shared
+        mmu::invalidate_tlb_asid(self.asid);
+        shared.map(|_| child)
+    }
+
+    fn share_pages(&mut self, child: &mut AddressSpace) -> Result<()> {
+        for virt in (0..USER_SPACE_END).step_by(SECTION_SIZE as usize) {
+            match unsafe { self.l1_entry(virt).read_volatile() } & L1_TYPE_MASK {
+                L1_FAULT => continue,
+                L1_PAGE_TABLE => {}
+                _ => return Err(MemoryError::SectionMapped),
+            }
+            for page in (virt..virt + SECTION_SIZE).step_by(PAGE_SIZE as usize) {
+                let entry = self.l2_entry(page, false)?;
+                let descriptor = unsafe { entry.read_volatile() };
+                if descriptor & SMALL_PAGE == 0 {
+                    continue;
+                }
+                let phys = descriptor & PAGE_BASE_MASK;
+                let frame = dram_frame(phys).ok_or(MemoryError::NotDram(phys))?;
+                // Read-only pages are marked as well, so protect can not make the shared frame writable
+                let write_after_copy = if descriptor & AP2 == 0 { WRITE_AFTER_COPY } else { 0 };
+                let shared = descriptor | AP2 | COPY_ON_WRITE | write_after_copy;
+                let child_entry = child.l2_entry(page, true)?;
+                PHYSICAL_MEMORY.lock().share_frame(frame)?;
+                unsafe {
+                    write_entry(child_entry, shared);
+                    write_entry(entry, shared);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve a write to the copy-on-write page at virt. The page gets a copy of the frame, the
+    /// last reference to a frame takes it over and only becomes writable again. Writes to pages
+    /// that were read-only when they were shared stay access violations.
+    pub(super) fn copy_on_write(&mut self, virt: u32) -> Result<()> {
+        let entry = self.l2_entry(virt, false)?;
+        let descriptor = unsafe { entry.read_volatile() };
+        if descriptor & SMALL_PAGE == 0 || descriptor & COPY_ON_WRITE == 0 || descriptor & WRITE_AFTER_COPY == 0 {
+            return Err(MemoryError::AccessViolation);
+        }
+        let frame = physical(descriptor & PAGE_BASE_MASK);
+        let writable = descriptor & !(PAGE_BASE_MASK | AP2 | COPY_ON_WRITE | WRITE_AFTER_COPY);
+        let mut physical_memory = PHYSICAL_MEMORY.lock();
+        if physical_memory.frame_info(frame)?.refcount() == 1 {
+            physical_memory.record_access(frame, true)?;
+            unsafe { write_entry(entry, frame.as_u32() | writable) };
+        } else {
+            let copy = physical_memory.allocate_frame(Owner::User)?.leak();
+            physical_memory.record_access(copy, true)?;
+            unsafe {
+                ptr::copy_nonoverlapping(linear_address(frame) as *const u8, linear_address(copy) as *mut u8, PAGE_SIZE as usize);
+                write_entry(entry, copy.as_u32() | writable);
+            }
+            physical_memory.release_frame(frame)?;
+        }
+        mmu::invalidate_tlb_entry(virt, self.asid);
+        Ok(())
+    }
+
+    /// Unmap all pages of a user address space and drop their references to the frames, pages
+    /// outside of DRAM are only unmapped
+    pub fn unmap_all(&mut self) -> Result<()> {
+        assert!(self.is_user(), "The kernel address space can not be unmapped");
+        for virt in (0..USER_SPACE_END).step_by(SECTION_SIZE as usize) {
+            if unsafe { self.l1_entry(virt).read_volatile() } & L1_TYPE_MASK != L1_PAGE_TABLE {
+                continue;
+            }
+            for page in (virt..virt + SECTION_SIZE).step_by(PAGE_SIZE as usize) {
+                match self.unmap_address(page) {
+                    Ok(phys) => {
+                        if let Some(frame) = dram_frame(phys) {
+                            PHYSICAL_MEMORY.lock().release_frame(frame)?;
+                        }
+                    }
+                    Err(MemoryError::NotMapped) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping of the page at virt, returns the frame it was mapped to.
+    /// The frame itself is not freed.
+    pub fn unmap(&mut self, virt: VirtualAddress) -> Result<PhysicalAddress> {
+        self.unmap_address(virt.as_u32()).map(physical)
+    }
+
+    /// Remove the mapping of the page at virt, which does not have to be backed by DRAM.
+    /// Returns the physical address it was mapped to. DRAM frames of writable pages are
+    /// considered dirty, the MMU does not tell whether they were written.
+    pub(super) fn unmap_address(&mut self, virt: u32) -> Result<u32> {
+        let entry = self.l2_entry(virt, false)?;
+        let descriptor = unsafe { entry.read_volatile() };
+        if descriptor & SMALL_PAGE == 0 {
+            return Err(MemoryError::NotMapped);
+        }
+        unsafe { write_entry(entry, 0) };
+        mmu::invalidate_tlb_entry(virt, self.asid);
+        self.free_empty_table(virt)?;
+        let phys = descriptor & PAGE_BASE_MASK;
+        if descriptor & AP2 == 0 {
+            if let Some(frame) = dram_frame(phys) {
+                PHYSICAL_MEMORY.lock().record_access(frame, true)?;
+            }
+        }
+        Ok(phys)
+    }
+
+    /// Change the attributes of the page at virt.
+    /// Copy-on-write pages stay read-only, if they are made writable the first write copies them.
+    pub fn protect(&mut self, virt: VirtualAddress, attributes: PageAttributes) -> Result<()> {
+        let virt = virt.as_u32();
+        let entry = self.l2_entry(virt, false)?;
+        let descriptor = unsafe { entry.read_volatile() };
+        if descriptor & SMALL_PAGE == 0 {
+            return Err(MemoryError::NotMapped);
+        }
+        let mut protected = small_page(descriptor & PAGE_BASE_MASK, attributes) | self.descriptor_flags();
+        // A shared frame only becomes writable through copy_on_write, otherwise writes would show
+        // up in every address space that maps it
+        if descriptor & COPY_ON_WRITE != 0 {
+            let write_after_copy = if protected & AP2 == 0 { WRITE_AFTER_COPY } else { 0 };
+            protected |= AP2 | COPY_ON_WRITE | write_after_copy;
+        }
+        unsafe { write_entry(entry, protected) };
+        mmu::invalidate_tlb_entry(virt, self.asid);
+        Ok(())
+    }
+
+    /// Give the second level table of virt back to PAGE_TABLES if it does not map anything
+    fn free_empty_table(&mut self, virt: u32) -> Result<()> {
+        let l1 = self.l1_entry(virt);
+        let table = physical(unsafe { l1.read_volatile() } & L1_TABLE_BASE_MASK);
+        let entries = linear_address(table) as *const u32;
+        if (0..L2_ENTRIES).any(|index| unsafe { entries.offset(index as isize).read_volatile() } != 0) {
+            return Ok(());
+        }
+        unsafe { write_entry(l1, 0) };
+        mmu::invalidate_tlb();
+        PAGE_TABLES.lock().free(table)
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Release the frames and free the tables of a user address space, the kernel address spaces
+    /// live forever
+    fn drop(&mut self) {
+        if !self.is_user() {
+            return;
+        }
+        // Stop translating through the tables before they are freed
+        let active = self.table | self.asid as u32;
+        if ACTIVE_USER_SPACE.compare_exchange(active, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            unsafe { mmu::set_ttbcr(TTBCR_N | TTBCR_PD0) };
+        }
+        super::fault::release_lazy_regions(self.asid);
+        if let Err(err) = self.unmap_all() {
+            panic!("Could not unmap the pages of ASID {}: {:?}", self.asid, err);
+        }
+        let l1 = linear_address(self.table_address()) as *const u32;
+        for index in 0..USER_TABLE_SIZE / 4 {
+            let descriptor = unsafe { l1.offset(index as isize).read_volatile() };
+            if descriptor & L1_TYPE_MASK == L1_PAGE_TABLE {
+                if let Err(err) = PAGE_TABLES.lock().free(physical(descriptor & L1_TABLE_BASE_MASK)) {
+                    panic!("Could not free page table of ASID {}: {:?}", self.asid, err);
+                }
+            }
+        }
+        mmu::invalidate_tlb_asid(self.asid);
+        if let Err(err) = PHYSICAL_MEMORY.lock().free_frames(self.table_address(), USER_TABLE_SIZE / physmem::FRAME_SIZE) {
+            panic!("Could not free translation table of ASID {}: {:?}", self.asid, err);
+        }
+        free_asid(self.asid);
+    }
+}
+
+/// The address space of the kernel, it starts out with the table set up by init.s
+pub static KERNEL_SPACE: IrqSafeMutex<AddressSpace> = IrqSafeMutex::new(AddressSpace {
+    table: memory_map::DRAM_START.as_u32() + memory_map::BOOT_TRANSLATION_TABLE_OFFSET,
+    asid: 0,
+});
+
+/// Map the first size bytes of DRAM with pages so that no page of the kernel image is both
+/// writable and executable. .text is read-only and executable, .rodata is read-only and everything
+/// else is writable.
+fn map_kernel_image(space: &mut AddressSpace, size: u32) -> Result<()> {
+    let text = kernel_info::ro_start().as_u32()..kernel_info::text_end().as_u32();
+    let rodata = kernel_info::text_end().as_u32()..kernel_info::ro_end().as_u32();
+    let code = PageAttributes::new(MemoryType::DRAM, Permission::ReadOnly).executable();
+    let read_only = PageAttributes::new(MemoryType::DRAM, Permission::ReadOnly);
+    let data = PageAttributes::new(MemoryType::DRAM, Permission::ReadWrite);
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        let virt = kernel_info::kernel_start() + offset;
+        let attributes = if text.contains(&virt.as_u32()) {
+            code
+        } else if rodata.contains(&virt.as_u32()) {
+            read_only
+        } else {
+            data
+        };
+        space.map(virt, memory_map::DRAM_START + offset, attributes)?;
+    }
+    Ok(())
+}
+
+/// Build the final kernel address space, enable TEX remap and switch to it.
+/// DRAM is mapped linearly at the start of the kernel with the memory type of its zone, only the
+/// kernel .text is executable. Devices mapped by ioremap before the switch stay mapped.
+/// The kernel is translated through TTBR1 from now on and TTBR0 is disabled until a user address
+/// space is switched to. The identity mapping of DRAM used while init.s enables the MMU is
+/// dropped, so physical addresses of DRAM fault from now on.
+pub fn init_kernel_space() -> Result<()> {
+    let mut kernel_space = KERNEL_SPACE.lock();
+    let mut space = AddressSpace::create()?;
+    let image_size = (kernel_info::kernel_memory_size() as u32 + SECTION_SIZE - 1) & !(SECTION_SIZE - 1);
+    map_kernel_image(&mut space, image_size)?;
+    for zone in [Zone::Normal, Zone::Dma].iter() {
+        let (start, end) = PHYSICAL_MEMORY.lock().zone_range(*zone);
+        // The kernel image is at the start of the normal zone
+        let start = if *zone == Zone::Normal { start + image_size } else { start };
+        if end.as_u32() <= start.as_u32() {
+            continue;
+        }
+        let size = end.as_u32() - start.as_u32();
+        let attributes = PageAttributes::new(zone_memory_type(*zone), Permission::ReadWrite);
+        let virt = kernel_info::kernel_start() + (start.as_u32() - memory_map::DRAM_START.as_u32());
+        space.map_sections(virt, start, size, attributes)?;
+    }
+    let device_window = memory_map::DEVICE_WINDOW_START..memory_map::DEVICE_WINDOW_START + memory_map::DEVICE_WINDOW_SIZE;
+    for virt in device_window.step_by(SECTION_SIZE as usize) {
+        space.share_l1_entry(&kernel_space, virt);
+    }
+    unsafe {
+        // The boot translation table uses TEX, C and B = 0 which is strongly-ordered either way
+        mmu::enable_tex_remap(PRRR, NMRR);
+        mmu::set_ttbr1(space.table | TTBR_FLAGS);
+        mmu::set_ttbcr(TTBCR_N | TTBCR_PD0);
+    }
+    *kernel_space = space;
+    Ok(())
+}
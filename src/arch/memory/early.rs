@@ -0,0 +1,84 @@
+//! Early boot allocator
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::{mem, ptr, slice};
+use armv7::PhysicalAddress;
+use physmem::bump::BumpAllocator;
+use crate::arch::sync::IrqSafeMutex;
+use crate::bsp::memory_map;
+use crate::kernel::kernel_info;
+use super::{MemoryError, Result};
+
+/// Memory after __bss_end, handed out until PHYSICAL_MEMORY takes over
+static EARLY_ALLOCATOR: IrqSafeMutex<Option<BumpAllocator>> = IrqSafeMutex::new(None);
+
+/// Start the early allocator right after the kernel image.
+/// Everything up to the end of DRAM can be used, it is reached through the linear mapping.
+pub fn init(dram_size: u32) {
+    let dram_start = memory_map::DRAM_START.as_u32();
+    let kernel_end = dram_start + kernel_info::kernel_memory_size() as u32;
+    let start = (kernel_end + physmem::FRAME_SIZE - 1) & !(physmem::FRAME_SIZE - 1);
+    *EARLY_ALLOCATOR.lock() = Some(BumpAllocator::new(start, dram_start + dram_size));
+}
+
+fn virtual_address(physical: u32) -> u32 {
+    physical - memory_map::DRAM_START.as_u32() + memory_map::KERNEL_VIRTUAL_START
+}
+
+/// Allocate zeroed memory before the frame allocator is running.
+/// The memory is never freed.
+pub fn alloc(size: u32, align: u32) -> Result<PhysicalAddress> {
+    let mut allocator = EARLY_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or(MemoryError::Unavailable)?;
+    let address = allocator.alloc(size, align).ok_or(MemoryError::OutOfMemory)?;
+    unsafe { ptr::write_bytes(virtual_address(address) as *mut u8, 0, size as usize) };
+    Ok(memory_map::DRAM_START + (address - memory_map::DRAM_START.as_u32()))
+}
+
+/// Stop the early allocator, returns the end of the memory it handed out.
+/// Later calls to alloc fail.
+pub fn finish() -> Result<PhysicalAddress> {
+    let allocator = EARLY_ALLOCATOR.lock().take().ok_or(MemoryError::Unavailable)?;
+    Ok(memory_map::DRAM_START + (allocator.next() - memory_map::DRAM_START.as_u32()))
+}
+
+/// A zeroed array from the early allocator that lives as long as the kernel
+pub struct EarlySlice<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T> EarlySlice<T> {
+    pub const fn empty() -> Self {
+        EarlySlice { ptr: ptr::null_mut(), len: 0 }
+    }
+
+    /// Allocate len elements, T has to be valid when zeroed
+    pub unsafe fn new(len: usize) -> Result<Self> {
+        let size = (len * mem::size_of::<T>()) as u32;
+        let address = alloc(size, mem::align_of::<T>() as u32)?;
+        Ok(EarlySlice { ptr: virtual_address(address.as_u32()) as *mut T, len: len })
+    }
+}
+
+impl<T> AsRef<[T]> for EarlySlice<T> {
+    fn as_ref(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T> AsMut<[T]> for EarlySlice<T> {
+    fn as_mut(&mut self) -> &mut [T] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+// The memory is owned exclusively by the slice
+unsafe impl<T: Send> Send for EarlySlice<T> {}
@@ -0,0 +1,276 @@
+//! Decoding of aborts and demand paging of lazily populated regions
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+use core::ptr;
+use armv7::VirtualAddress;
+use crate::arch::mmu;
+use crate::arch::sync::IrqSafeMutex;
+use super::address_space::{active_asid, with_address_space};
+use super::{allocate_frame, linear_address, virtual_address, AddressSpace, MemoryError, Owner, PageAttributes, Permission, Result};
+use super::{PAGE_SIZE, PAGE_TABLES, PHYSICAL_MEMORY, USER_SPACE_END};
+
+/// Maximal number of lazily populated regions of all address spaces
+pub const MAX_LAZY_REGIONS: usize = 32;
+
+/// Write not Read bit of the DFSR
+const DFSR_WNR: u32 = 1 << 11;
+
+/// Whether a fault was caused by a section or by a page
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Level {
+    Section,
+    Page,
+}
+
+/// Fault status of the short descriptor format
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum FaultStatus {
+    Alignment,
+    Debug,
+    CacheMaintenance,
+    Translation(Level),
+    AccessFlag(Level),
+    Domain(Level),
+    Permission(Level),
+    /// Synchronous external abort on a translation table walk
+    TableWalk(Level),
+    ExternalAbort,
+    AsyncExternalAbort,
+    Parity,
+    Unknown(u32),
+}
+
+impl FaultStatus {
+    /// Decode FS[4:0] of a DFSR or IFSR
+    pub fn decode(fsr: u32) -> FaultStatus {
+        match ((fsr >> 6) & 0x10) | (fsr & 0xf) {
+            0b00001 => FaultStatus::Alignment,
+            0b00010 => FaultStatus::Debug,
+            0b00100 => FaultStatus::CacheMaintenance,
+            0b00101 => FaultStatus::Translation(Level::Section),
+            0b00111 => FaultStatus::Translation(Level::Page),
+            0b00011 => FaultStatus::AccessFlag(Level::Section),
+            0b00110 => FaultStatus::AccessFlag(Level::Page),
+            0b01001 => FaultStatus::Domain(Level::Section),
+            0b01011 => FaultStatus::Domain(Level::Page),
+            0b01101 => FaultStatus::Permission(Level::Section),
+            0b01111 => FaultStatus::Permission(Level::Page),
+            0b01100 => FaultStatus::TableWalk(Level::Section),
+            0b01110 => FaultStatus::TableWalk(Level::Page),
+            0b01000 => FaultStatus::ExternalAbort,
+            0b10110 => FaultStatus::AsyncExternalAbort,
+            0b11000 | 0b11001 | 0b11100 | 0b11110 => FaultStatus::Parity,
+            status => FaultStatus::Unknown(status),
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "instruction fetch",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A decoded data or prefetch abort
+#[derive(Copy,Clone,Debug)]
+pub struct Fault {
+    pub address: u32,
+    pub access: Access,
+    pub status: FaultStatus,
+    /// The raw DFSR or IFSR
+    pub fsr: u32,
+}
+
+impl Fault {
+    /// The last data abort, only valid in the data abort handler
+    pub fn data() -> Fault {
+        let dfsr = mmu::read_dfsr();
+        Fault {
+            address: mmu::read_dfar(),
+            access: if dfsr & DFSR_WNR != 0 { Access::Write } else { Access::Read },
+            status: FaultStatus::decode(dfsr),
+            fsr: dfsr,
+        }
+    }
+
+    /// The last prefetch abort, only valid in the prefetch abort handler
+    pub fn prefetch() -> Fault {
+        let ifsr = mmu::read_ifsr();
+        Fault {
+            address: mmu::read_ifar(),
+            access: Access::Execute,
+            status: FaultStatus::decode(ifsr),
+            fsr: ifsr,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} fault on {} of {:#010x} (FSR {:#x})", self.status, self.access, self.address, self.fsr)?;
+        if let FaultStatus::Domain(_) = self.status {
+            write!(f, " in domain {}", (self.fsr >> 4) & 0xf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Virtual addresses of an address space that are backed with zeroed frames on first access
+#[derive(Copy,Clone,Debug)]
+struct LazyRegion {
+    asid: u8,
+    start: u32,
+    size: u32,
+    attributes: PageAttributes,
+}
+
+impl LazyRegion {
+    fn contains(&self, asid: u8, address: u32) -> bool {
+        self.asid == asid && self.start <= address && address - self.start < self.size
+    }
+
+    /// Instruction fetches are never resolved, the frames are not made visible to the
+    /// instruction cache
+    fn allows(&self, access: Access) -> bool {
+        match (access, self.attributes.perms) {
+            (Access::Execute, _) => false,
+            (Access::Write, Permission::ReadOnly) => false,
+            _ => true,
+        }
+    }
+}
+
+static LAZY_REGIONS: IrqSafeMutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = IrqSafeMutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Populate size bytes at start of space on demand. The addresses must not be mapped already.
+/// Accesses while one of the memory management locks is held are not resolved.
+pub fn register_lazy_region(space: &AddressSpace, start: VirtualAddress, size: u32, attributes: PageAttributes) -> Result<()> {
+    let start = start.as_u32();
+    if start % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+        return Err(MemoryError::NotAligned);
+    }
+    if size == 0 {
+        return Err(MemoryError::InvalidSize);
+    }
+    let end = start.checked_add(size).ok_or(MemoryError::NotInRange)?;
+    let in_range = if space.asid() == 0 { start >= USER_SPACE_END } else { end <= USER_SPACE_END };
+    if !in_range {
+        return Err(MemoryError::NotInRange);
+    }
+    let mut regions = LAZY_REGIONS.lock();
+    let overlaps = regions.iter().filter_map(|slot| slot.as_ref())
+        .any(|region| region.asid == space.asid() && region.start < end && start < region.start + region.size);
+    if overlaps {
+        return Err(MemoryError::Overlap);
+    }
+    let slot = regions.iter_mut().find(|slot| slot.is_none()).ok_or(MemoryError::TooManyRegions)?;
+    *slot = Some(LazyRegion { asid: space.asid(), start: start, size: size, attributes: attributes });
+    Ok(())
+}
+
+/// Remove the lazy region starting at start and free the frames that were populated
+pub fn unregister_lazy_region(space: &mut AddressSpace, start: VirtualAddress) -> Result<()> {
+    let region = {
+        let mut regions = LAZY_REGIONS.lock();
+        let slot = regions.iter_mut()
+            .find(|slot| slot.map_or(false, |region| region.asid == space.asid() && region.start == start.as_u32()))
+            .ok_or(MemoryError::NotInRange)?;
+        slot.take().unwrap()
+    };
+    for page in (region.start..region.start + region.size).step_by(PAGE_SIZE as usize) {
+        match space.unmap(virtual_address(page)) {
+            Ok(frame) => {
+                PHYSICAL_MEMORY.lock().release_frame(frame)?;
+            }
+            Err(MemoryError::NotMapped) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Forget all lazy regions of a user address space that is freed
+pub(super) fn release_lazy_regions(asid: u8) {
+    for slot in LAZY_REGIONS.lock().iter_mut() {
+        if slot.map_or(false, |region| region.asid == asid) {
+            *slot = None;
+        }
+    }
+}
+
+/// Copy the lazy regions of the address space with ASID from to the one with ASID to, nothing is
+/// copied if there are not enough free slots
+pub(super) fn copy_lazy_regions(from: u8, to: u8) -> Result<()> {
+    let mut regions = LAZY_REGIONS.lock();
+    let needed = regions.iter().filter(|slot| slot.map_or(false, |region| region.asid == from)).count();
+    if regions.iter().filter(|slot| slot.is_none()).count() < needed {
+        return Err(MemoryError::TooManyRegions);
+    }
+    for index in 0..MAX_LAZY_REGIONS {
+        if let Some(region) = regions[index].filter(|region| region.asid == from) {
+            let slot = regions.iter_mut().find(|slot| slot.is_none()).unwrap();
+            *slot = Some(LazyRegion { asid: to, ..region });
+        }
+    }
+    Ok(())
+}
+
+fn find_lazy_region(asid: u8, address: u32) -> Result<Option<LazyRegion>> {
+    let regions = LAZY_REGIONS.try_lock().ok_or(MemoryError::Locked("LAZY_REGIONS"))?;
+    Ok(regions.iter().filter_map(|slot| *slot).find(|region| region.contains(asid, address)))
+}
+
+/// Fail if a lock that resolving a fault takes is held by the faulting code.
+/// Aborts are handled with IRQs masked on the only core, so a lock that is free here stays free
+/// until the fault is resolved.
+fn check_unlocked<T>(lock: &IrqSafeMutex<T>, name: &'static str) -> Result<()> {
+    lock.try_lock().map(|_| ()).ok_or(MemoryError::Locked(name))
+}
+
+/// Try to resolve an abort, the faulting instruction can be executed again on success.
+/// Translation faults in lazy regions of the kernel or the active user address space are
+/// resolved by mapping a zeroed frame, writes to copy-on-write pages by copying the frame.
+/// Faults of code that holds one of the locks needed for that are never resolved.
+pub fn resolve(fault: &Fault) -> Result<()> {
+    let page = fault.address & !(PAGE_SIZE - 1);
+    let copy_on_write = match (fault.status, fault.access) {
+        (FaultStatus::Translation(_), _) => false,
+        (FaultStatus::Permission(Level::Page), Access::Write) => true,
+        _ => return Err(MemoryError::AccessViolation),
+    };
+    check_unlocked(&PAGE_TABLES, "PAGE_TABLES")?;
+    check_unlocked(&PHYSICAL_MEMORY, "PHYSICAL_MEMORY")?;
+    if copy_on_write {
+        return with_address_space(page, |space| space.copy_on_write(page));
+    }
+    let asid = if page >= USER_SPACE_END { 0 } else { active_asid() };
+    let region = find_lazy_region(asid, page)?.ok_or(MemoryError::NotMapped)?;
+    if !region.allows(fault.access) {
+        return Err(MemoryError::AccessViolation);
+    }
+    with_address_space(page, |space| populate(space, page, region.attributes, fault.access))
+}
+
+fn populate(space: &mut AddressSpace, page: u32, attributes: PageAttributes, access: Access) -> Result<()> {
+    let owner = if space.asid() == 0 { Owner::Heap } else { Owner::User };
+    let frame = allocate_frame(owner)?;
+    unsafe { ptr::write_bytes(linear_address(frame.address()) as *mut u8, 0, PAGE_SIZE as usize) };
+    PHYSICAL_MEMORY.lock().record_access(frame.address(), access == Access::Write)?;
+    space.map(virtual_address(page), frame.address(), attributes)?;
+    frame.leak();
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! Mappings of peripheral registers
+// Author: Moritz Doll
+// License: GPLv3
+
+use armv7::VirtualAddress;
+use super::vmem::{self, Window};
+use super::{virtual_address, MemoryError, MemoryType, PageAttributes, Permission, Result, KERNEL_SPACE, PAGE_SIZE};
+
+/// Map size bytes of device registers at the physical address phys into the device window.
+/// The pages are Device memory and never executable, the returned address has the same offset
+/// into its page as phys.
+pub fn ioremap(phys: u32, size: u32) -> Result<VirtualAddress> {
+    let offset = phys % PAGE_SIZE;
+    let start = phys - offset;
+    let size = size.checked_add(offset + PAGE_SIZE - 1).ok_or(MemoryError::InvalidSize)? & !(PAGE_SIZE - 1);
+    // The last page may end exactly at the top of the address space
+    if size == 0 || start.checked_add(size - 1).is_none() {
+        return Err(MemoryError::InvalidSize);
+    }
+    let virt = vmem::allocate_range(Window::Device, size, PAGE_SIZE)?.as_u32();
+    let attributes = PageAttributes::new(MemoryType::Device, Permission::ReadWrite);
+    let mut kernel_space = KERNEL_SPACE.lock();
+    for page in (0..size).step_by(PAGE_SIZE as usize) {
+        if let Err(err) = kernel_space.map_address(virt + page, start + page, attributes) {
+            // Nothing stays mapped on errors
+            for mapped in (0..page).step_by(PAGE_SIZE as usize) {
+                kernel_space.unmap_address(virt + mapped)?;
+            }
+            drop(kernel_space);
+            vmem::free_range(Window::Device, virtual_address(virt))?;
+            return Err(err);
+        }
+    }
+    Ok(virtual_address(virt + offset))
+}
+
+/// Remove a mapping created by ioremap
+pub fn iounmap(virt: VirtualAddress) -> Result<()> {
+    let range = vmem::find_range(Window::Device, virt).ok_or(MemoryError::NotInRange)?;
+    {
+        let mut kernel_space = KERNEL_SPACE.lock();
+        for page in (range.start..range.end()).step_by(PAGE_SIZE as usize) {
+            kernel_space.unmap_address(page)?;
+        }
+    }
+    vmem::free_range(Window::Device, virtual_address(range.start)).map(|_| ())
+}
@@ -0,0 +1,35 @@
+//! Memory poisoning, enabled by the poison feature
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt::Write;
+use armv7::PhysicalAddress;
+use crate::driver;
+use super::linear_address;
+
+/// Written to memory when it is handed out, catches reads of uninitialized memory
+pub const ALLOC_PATTERN: u32 = 0xCDCD_CDCD;
+/// Written to memory when it is freed, catches use after free
+pub const FREE_PATTERN: u32 = 0xDDDD_DDDD;
+
+/// Fill size bytes at address with pattern, size has to be a multiple of 4
+pub unsafe fn fill(address: PhysicalAddress, size: u32, pattern: u32) {
+    let start = linear_address(address) as *mut u32;
+    for word in 0..(size / 4) as isize {
+        start.offset(word).write_volatile(pattern);
+    }
+}
+
+/// Offset of the first word of size bytes at address that does not contain pattern
+pub unsafe fn check(address: PhysicalAddress, size: u32, pattern: u32) -> Option<u32> {
+    let start = linear_address(address) as *const u32;
+    (0..size / 4).find(|word| start.offset(*word as isize).read_volatile() != pattern).map(|word| word * 4)
+}
+
+/// Print a corrupted frame directly to the UART, the caller may hold any lock
+pub fn report(frame: PhysicalAddress, offset: u32) {
+    let mut serial = driver::console();
+    let value = unsafe { (linear_address(frame + offset) as *const u32).read_volatile() };
+    writeln!(serial, "Memory corruption: free frame {:#x} was written at offset {:#x}, found {:#010x}",
+             frame, offset, value).ok();
+}
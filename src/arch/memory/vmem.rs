@@ -0,0 +1,159 @@
+//! Windows of kernel virtual addresses
+// Author: Moritz Doll
+// License: GPLv3
+
+use armv7::VirtualAddress;
+use physmem::range::{Range, RangeAllocator};
+use crate::arch::sync::IrqSafeMutex;
+use crate::bsp::memory_map;
+use super::{allocate_frame, virtual_address, MemoryError, MemoryType, Owner, PageAttributes, Permission, Result};
+use super::{KERNEL_SPACE, PAGE_SIZE, PHYSICAL_MEMORY};
+
+/// Parts of the kernel address space above the linear mapping of DRAM
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Window {
+    Vmalloc,
+    Stacks,
+    Device,
+    Fixmap,
+}
+
+struct Windows {
+    vmalloc: RangeAllocator,
+    stacks: RangeAllocator,
+    device: RangeAllocator,
+    fixmap: RangeAllocator,
+}
+
+impl Windows {
+    fn get(&mut self, window: Window) -> &mut RangeAllocator {
+        match window {
+            Window::Vmalloc => &mut self.vmalloc,
+            Window::Stacks => &mut self.stacks,
+            Window::Device => &mut self.device,
+            Window::Fixmap => &mut self.fixmap,
+        }
+    }
+}
+
+static WINDOWS: IrqSafeMutex<Windows> = IrqSafeMutex::new(Windows {
+    vmalloc: RangeAllocator::new(memory_map::VMALLOC_START, memory_map::VMALLOC_SIZE),
+    stacks: RangeAllocator::new(memory_map::KERNEL_STACKS_START, memory_map::KERNEL_STACKS_SIZE),
+    device: RangeAllocator::new(memory_map::DEVICE_WINDOW_START, memory_map::DEVICE_WINDOW_SIZE),
+    fixmap: RangeAllocator::new(memory_map::FIXMAP_START, memory_map::FIXMAP_SIZE),
+});
+
+fn range_error(err: physmem::Error) -> MemoryError {
+    match err {
+        physmem::Error::OutOfMemory => MemoryError::OutOfMemory,
+        physmem::Error::InvalidSize => MemoryError::InvalidSize,
+        physmem::Error::Overlap => MemoryError::Overlap,
+        physmem::Error::TooManyRegions => MemoryError::TooManyRegions,
+        // The range allocator reports nothing else
+        _ => MemoryError::NotInRange,
+    }
+}
+
+/// size rounded up to whole pages, zero and sizes that leave no room for a guard page are invalid
+fn round_up(size: u32) -> Result<u32> {
+    if size == 0 || size > u32::max_value() - 2 * PAGE_SIZE {
+        return Err(MemoryError::InvalidSize);
+    }
+    Ok((size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+}
+
+/// Take size bytes of virtual addresses from a window, nothing is mapped
+pub fn allocate_range(window: Window, size: u32, align: u32) -> Result<VirtualAddress> {
+    let start = WINDOWS.lock().get(window).alloc(size, align).map_err(range_error)?;
+    Ok(virtual_address(start))
+}
+
+/// Take the fixed range of size bytes at start from a window
+pub fn reserve_range(window: Window, start: u32, size: u32) -> Result<()> {
+    WINDOWS.lock().get(window).reserve(start, size).map_err(range_error)
+}
+
+/// Give back the range starting at start, returns its size.
+/// The pages of the range have to be unmapped before.
+pub fn free_range(window: Window, start: VirtualAddress) -> Result<u32> {
+    WINDOWS.lock().get(window).free(start.as_u32()).map_err(range_error)
+}
+
+/// The range of a window containing address
+pub fn find_range(window: Window, address: VirtualAddress) -> Option<Range> {
+    WINDOWS.lock().get(window).find(address.as_u32())
+}
+
+/// Back size bytes at start with newly allocated frames, nothing stays mapped on errors
+fn map_new_frames(start: u32, size: u32, attributes: PageAttributes) -> Result<()> {
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        let frame = match allocate_frame(Owner::Heap) {
+            Ok(frame) => frame,
+            Err(err) => {
+                release_frames(start, offset)?;
+                return Err(err);
+            }
+        };
+        // The lock has to be dropped before release_frames takes it again
+        let mapped = KERNEL_SPACE.lock().map(virtual_address(start + offset), frame.address(), attributes);
+        if let Err(err) = mapped {
+            release_frames(start, offset)?;
+            return Err(err);
+        }
+        frame.leak();
+    }
+    Ok(())
+}
+
+/// Unmap size bytes at start and free the frames behind them
+fn release_frames(start: u32, size: u32) -> Result<()> {
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        let frame = KERNEL_SPACE.lock().unmap(virtual_address(start + offset))?;
+        PHYSICAL_MEMORY.lock().free_frame(frame)?;
+    }
+    Ok(())
+}
+
+/// Allocate size bytes of virtually contiguous memory.
+/// The frames behind it are allocated one by one and are not physically contiguous, the range is
+/// followed by an unmapped guard page.
+pub fn vmalloc(size: u32) -> Result<VirtualAddress> {
+    let size = round_up(size)?;
+    let start = allocate_range(Window::Vmalloc, size + PAGE_SIZE, PAGE_SIZE)?;
+    let attributes = PageAttributes::new(MemoryType::DRAM, Permission::ReadWrite);
+    if let Err(err) = map_new_frames(start.as_u32(), size, attributes) {
+        free_range(Window::Vmalloc, start)?;
+        return Err(err);
+    }
+    Ok(start)
+}
+
+/// Free memory allocated by vmalloc
+pub fn vfree(start: VirtualAddress) -> Result<()> {
+    let range = find_range(Window::Vmalloc, start).ok_or(MemoryError::NotInRange)?;
+    if range.start != start.as_u32() {
+        return Err(MemoryError::NotInRange);
+    }
+    release_frames(range.start, range.size - PAGE_SIZE)?;
+    free_range(Window::Vmalloc, start).map(|_| ())
+}
+
+/// Allocate a kernel stack of size bytes and return its top.
+/// The page below the stack stays unmapped, so an overflow faults.
+pub fn allocate_stack(size: u32) -> Result<VirtualAddress> {
+    let size = round_up(size)?;
+    let guard = allocate_range(Window::Stacks, size + PAGE_SIZE, PAGE_SIZE)?;
+    let attributes = PageAttributes::new(MemoryType::DRAM, Permission::ReadWrite);
+    if let Err(err) = map_new_frames(guard.as_u32() + PAGE_SIZE, size, attributes) {
+        free_range(Window::Stacks, guard)?;
+        return Err(err);
+    }
+    Ok(guard + PAGE_SIZE + size)
+}
+
+/// Free a stack, top is the address returned by allocate_stack
+pub fn free_stack(top: VirtualAddress) -> Result<()> {
+    let range = find_range(Window::Stacks, virtual_address(top.as_u32() - 1)).ok_or(MemoryError::NotInRange)?;
+    release_frames(range.start + PAGE_SIZE, range.size - PAGE_SIZE)?;
+    free_range(Window::Stacks, virtual_address(range.start)).map(|_| ())
+}
@@ -0,0 +1,272 @@
+//! Software walk of the translation tables for debugging
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+use armv7::VirtualAddress;
+use crate::arch::mmu;
+use crate::bsp::memory_map;
+use super::address_space::with_address_space;
+use super::{linear_address, AddressSpace, MemoryError, Result, USER_SPACE_END};
+
+const SECTION_SHIFT: u32 = 20;
+const SECTION_SIZE: u32 = 1 << SECTION_SHIFT;
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+const L1_ENTRIES: u32 = 4096;
+const L2_ENTRIES: u32 = 256;
+
+// Every supersection and large page descriptor is repeated in 16 consecutive entries
+const SUPERSECTION_SIZE: u32 = 16 * SECTION_SIZE;
+const LARGE_PAGE_SIZE: u32 = 16 * PAGE_SIZE;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Kind {
+    Supersection,
+    Section,
+    LargePage,
+    SmallPage,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Supersection => "supersection",
+            Kind::Section => "section",
+            Kind::LargePage => "large page",
+            Kind::SmallPage => "small page",
+        };
+        f.pad(name)
+    }
+}
+
+/// Fields of a descriptor that are independent of its format
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Attributes {
+    /// AP[2:0]
+    pub ap: u8,
+    pub xn: bool,
+    pub tex: u8,
+    pub c: bool,
+    pub b: bool,
+    pub s: bool,
+    pub ng: bool,
+    pub domain: u8,
+}
+
+impl Attributes {
+    /// Whether a privileged read is allowed, i.e. ATS1CPR does not fault
+    fn privileged_read(&self) -> bool {
+        self.ap != 0b000 && self.ap != 0b100
+    }
+}
+
+/// Privileged and user permissions of AP[2:0]
+fn access_permissions(ap: u8) -> &'static str {
+    match ap {
+        0b000 => "--/--",
+        0b001 => "RW/--",
+        0b010 => "RW/RO",
+        0b011 => "RW/RW",
+        0b101 => "RO/--",
+        0b110 | 0b111 => "RO/RO",
+        _ => "reserved",
+    }
+}
+
+fn flag(set: bool, name: &'static str) -> &'static str {
+    if set { name } else { "-" }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<8} {:<2} TEX {:03b} {} {} {} {:<2} domain {}",
+            access_permissions(self.ap), flag(self.xn, "XN"), self.tex, flag(self.c, "C"), flag(self.b, "B"),
+            flag(self.s, "S"), flag(self.ng, "nG"), self.domain)
+    }
+}
+
+/// Virtually and physically contiguous addresses with the same attributes
+#[derive(Copy,Clone,Debug)]
+pub struct Mapping {
+    pub virt: u32,
+    pub phys: u32,
+    pub size: u32,
+    pub kind: Kind,
+    pub attributes: Attributes,
+}
+
+impl Mapping {
+    fn extends(&self, next: &Mapping) -> bool {
+        self.kind == next.kind
+            && self.attributes == next.attributes
+            && self.virt.wrapping_add(self.size) == next.virt
+            && self.phys.wrapping_add(self.size) == next.phys
+    }
+
+    /// Physical address of virt, which has to be inside the mapping
+    pub fn physical(&self, virt: u32) -> u32 {
+        self.phys + (virt - self.virt)
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x} - {:#010x} -> {:#010x} {:<12} {}",
+            self.virt, self.virt + (self.size - 1), self.phys, self.kind, self.attributes)
+    }
+}
+
+fn bit(descriptor: u32, bit: u32) -> bool {
+    descriptor & (1 << bit) != 0
+}
+
+/// The megabyte at virt mapped by a section or supersection descriptor
+fn section(virt: u32, descriptor: u32) -> Mapping {
+    let supersection = bit(descriptor, 18);
+    let phys = if supersection {
+        (descriptor & !(SUPERSECTION_SIZE - 1)) + (virt & (SUPERSECTION_SIZE - 1))
+    } else {
+        descriptor & !(SECTION_SIZE - 1)
+    };
+    Mapping {
+        virt: virt,
+        phys: phys,
+        size: SECTION_SIZE,
+        kind: if supersection { Kind::Supersection } else { Kind::Section },
+        attributes: Attributes {
+            ap: ((((descriptor >> 15) & 0b1) << 2) | ((descriptor >> 10) & 0b11)) as u8,
+            xn: bit(descriptor, 4),
+            tex: ((descriptor >> 12) & 0b111) as u8,
+            c: bit(descriptor, 3),
+            b: bit(descriptor, 2),
+            s: bit(descriptor, 16),
+            ng: bit(descriptor, 17),
+            // Supersections are always in domain 0
+            domain: if supersection { 0 } else { ((descriptor >> 5) & 0xf) as u8 },
+        },
+    }
+}
+
+/// The page at virt mapped by a second level descriptor, None if it is not mapped
+fn page(virt: u32, descriptor: u32, domain: u8) -> Option<Mapping> {
+    let (kind, phys, xn, tex) = match descriptor & 0b11 {
+        0b00 => return None,
+        0b01 => (Kind::LargePage, (descriptor & !(LARGE_PAGE_SIZE - 1)) + (virt & (LARGE_PAGE_SIZE - 1)), bit(descriptor, 15), (descriptor >> 12) & 0b111),
+        _ => (Kind::SmallPage, descriptor & !(PAGE_SIZE - 1), bit(descriptor, 0), (descriptor >> 6) & 0b111),
+    };
+    Some(Mapping {
+        virt: virt,
+        phys: phys,
+        size: PAGE_SIZE,
+        kind: kind,
+        attributes: Attributes {
+            ap: ((((descriptor >> 9) & 0b1) << 2) | ((descriptor >> 4) & 0b11)) as u8,
+            xn: xn,
+            tex: tex as u8,
+            c: bit(descriptor, 3),
+            b: bit(descriptor, 2),
+            s: bit(descriptor, 10),
+            ng: bit(descriptor, 11),
+            domain: domain,
+        },
+    })
+}
+
+/// Number of first level entries, the tables of user address spaces are smaller
+fn l1_entries(space: &AddressSpace) -> u32 {
+    if space.asid() == 0 { L1_ENTRIES } else { USER_SPACE_END >> SECTION_SHIFT }
+}
+
+fn l1_descriptor(space: &AddressSpace, index: u32) -> u32 {
+    let l1 = linear_address(space.table_address()) as *const u32;
+    unsafe { l1.offset(index as isize).read_volatile() }
+}
+
+fn l2_descriptor(l1_descriptor: u32, index: u32) -> u32 {
+    // Second level tables are always allocated from DRAM
+    let offset = (l1_descriptor & !0x3ff) - memory_map::DRAM_START.as_u32();
+    let l2 = linear_address(memory_map::DRAM_START + offset) as *const u32;
+    unsafe { l2.offset(index as isize).read_volatile() }
+}
+
+/// Call f for every mapping of space in ascending order. Entries that continue each other
+/// virtually and physically with the same kind and attributes are merged into one mapping.
+pub fn walk<F: FnMut(&Mapping)>(space: &AddressSpace, mut f: F) {
+    let mut merged: Option<Mapping> = None;
+    let mut push = |mapping: Mapping| {
+        match merged {
+            Some(ref mut current) if current.extends(&mapping) => current.size += mapping.size,
+            _ => {
+                if let Some(current) = merged.replace(mapping) {
+                    f(&current);
+                }
+            }
+        }
+    };
+    for index in 0..l1_entries(space) {
+        let virt = index << SECTION_SHIFT;
+        let descriptor = l1_descriptor(space, index);
+        match descriptor & 0b11 {
+            0b00 => {}
+            0b01 => {
+                let domain = ((descriptor >> 5) & 0xf) as u8;
+                for l2_index in 0..L2_ENTRIES {
+                    let page_virt = virt + (l2_index << PAGE_SHIFT);
+                    if let Some(mapping) = page(page_virt, l2_descriptor(descriptor, l2_index), domain) {
+                        push(mapping);
+                    }
+                }
+            }
+            _ => push(section(virt, descriptor)),
+        }
+    }
+    if let Some(current) = merged {
+        f(&current);
+    }
+}
+
+/// The entry of space that maps virt
+pub fn lookup(space: &AddressSpace, virt: u32) -> Option<Mapping> {
+    let index = virt >> SECTION_SHIFT;
+    if index >= l1_entries(space) {
+        return None;
+    }
+    let descriptor = l1_descriptor(space, index);
+    match descriptor & 0b11 {
+        0b00 => None,
+        0b01 => {
+            let page_virt = virt & !(PAGE_SIZE - 1);
+            let l2_index = (virt >> PAGE_SHIFT) & (L2_ENTRIES - 1);
+            page(page_virt, l2_descriptor(descriptor, l2_index), ((descriptor >> 5) & 0xf) as u8)
+        }
+        _ => Some(section(virt & !(SECTION_SIZE - 1), descriptor)),
+    }
+}
+
+/// Print all mappings of space
+pub fn dump<T: fmt::Write>(serial: &mut T, space: &AddressSpace) -> fmt::Result {
+    writeln!(serial, "Translation table at {:#x}:", space.table_address())?;
+    let mut result = Ok(());
+    walk(space, |mapping| {
+        if result.is_ok() {
+            result = writeln!(serial, "    {}", mapping);
+        }
+    });
+    result
+}
+
+/// Physical address of virt in the active address spaces. The software walk is cross-checked
+/// against the translation of the hardware for a privileged read.
+pub fn translate(virt: VirtualAddress) -> Result<u32> {
+    let virt = virt.as_u32();
+    let software = with_address_space(virt, |space| Ok(lookup(space, virt)))?
+        .filter(|mapping| mapping.attributes.privileged_read())
+        .map(|mapping| mapping.physical(virt));
+    let hardware = mmu::translate_address(virt);
+    match (software, hardware) {
+        (Some(software), Some(hardware)) if software == hardware => Ok(software),
+        (None, None) => Err(MemoryError::NotMapped),
+        (software, hardware) => Err(MemoryError::TranslationMismatch(software, hardware)),
+    }
+}
@@ -0,0 +1,219 @@
+//! Maintenance operations of the MMU and the caches
+// Author: Moritz Doll
+// License: GPLv3
+
+const SCTLR_C: u32 = 1 << 2;
+const SCTLR_Z: u32 = 1 << 11;
+const SCTLR_I: u32 = 1 << 12;
+const SCTLR_TRE: u32 = 1 << 28;
+
+/// Size of a cache line of the Cortex-A8
+const CACHE_LINE_SIZE: u32 = 64;
+
+#[inline]
+fn read_sctlr() -> u32 {
+    let sctlr: u32;
+    unsafe { asm!("mrc p15, 0, $0, c1, c0, 0" : "=r"(sctlr) ::: "volatile") };
+    sctlr
+}
+
+#[inline]
+unsafe fn write_sctlr(sctlr: u32) {
+    asm!("mcr p15, 0, $0, c1, c0, 0
+          isb" :: "r"(sctlr) : "memory" : "volatile");
+}
+
+/// Invalidate the whole unified TLB
+#[inline]
+pub fn invalidate_tlb() {
+    unsafe {
+        asm!("dsb
+              mcr p15, 0, $0, c8, c7, 0
+              dsb
+              isb" :: "r"(0) : "memory" : "volatile");
+    }
+}
+
+/// Invalidate the TLB entries of the page containing address.
+/// Global entries are invalidated for every ASID.
+#[inline]
+pub fn invalidate_tlb_entry(address: u32, asid: u8) {
+    unsafe {
+        asm!("dsb
+              mcr p15, 0, $0, c8, c7, 1
+              dsb
+              isb" :: "r"((address & !0xfff) | asid as u32) : "memory" : "volatile");
+    }
+}
+
+/// Invalidate all non-global TLB entries of an ASID
+#[inline]
+pub fn invalidate_tlb_asid(asid: u8) {
+    unsafe {
+        asm!("dsb
+              mcr p15, 0, $0, c8, c7, 2
+              dsb
+              isb" :: "r"(asid as u32) : "memory" : "volatile");
+    }
+}
+
+/// Set the first level table of the kernel, the whole TLB is invalidated
+#[inline]
+pub unsafe fn set_ttbr1(ttbr: u32) {
+    asm!("dsb
+          mcr p15, 0, $0, c2, c0, 1
+          isb" :: "r"(ttbr) : "memory" : "volatile");
+    invalidate_tlb();
+}
+
+/// Set the split between TTBR0 and TTBR1, the whole TLB is invalidated
+#[inline]
+pub unsafe fn set_ttbcr(ttbcr: u32) {
+    asm!("mcr p15, 0, $0, c2, c0, 2
+          isb" :: "r"(ttbcr) : "memory" : "volatile");
+    invalidate_tlb();
+}
+
+/// Switch TTBR0 and the ASID in CONTEXTIDR without invalidating the TLB.
+/// Table walks through TTBR0 are disabled in between, so no entry of the new ASID can be created
+/// from the old table. ttbcr is the value to use afterwards.
+#[inline]
+pub unsafe fn switch_ttbr0(ttbr: u32, asid: u8, ttbcr: u32) {
+    const TTBCR_PD0: u32 = 1 << 4;
+    asm!("dsb
+          mcr p15, 0, $3, c2, c0, 2
+          isb
+          mcr p15, 0, $1, c13, c0, 1
+          isb
+          mcr p15, 0, $0, c2, c0, 0
+          isb
+          mcr p15, 0, $2, c2, c0, 2
+          isb" :: "r"(ttbr), "r"(asid as u32), "r"(ttbcr), "r"(ttbcr | TTBCR_PD0) : "memory" : "volatile");
+}
+
+/// Data fault status register of the last data abort
+#[inline]
+pub fn read_dfsr() -> u32 {
+    let dfsr: u32;
+    unsafe { asm!("mrc p15, 0, $0, c5, c0, 0" : "=r"(dfsr) ::: "volatile") };
+    dfsr
+}
+
+/// Address that caused the last data abort
+#[inline]
+pub fn read_dfar() -> u32 {
+    let dfar: u32;
+    unsafe { asm!("mrc p15, 0, $0, c6, c0, 0" : "=r"(dfar) ::: "volatile") };
+    dfar
+}
+
+/// Instruction fault status register of the last prefetch abort
+#[inline]
+pub fn read_ifsr() -> u32 {
+    let ifsr: u32;
+    unsafe { asm!("mrc p15, 0, $0, c5, c0, 1" : "=r"(ifsr) ::: "volatile") };
+    ifsr
+}
+
+/// Address of the instruction fetch that caused the last prefetch abort
+#[inline]
+pub fn read_ifar() -> u32 {
+    let ifar: u32;
+    unsafe { asm!("mrc p15, 0, $0, c6, c0, 2" : "=r"(ifar) ::: "volatile") };
+    ifar
+}
+
+/// Translate address with the current tables like a privileged read (ATS1CPR).
+/// Returns the physical address from the PAR or None if the translation faults.
+pub fn translate_address(address: u32) -> Option<u32> {
+    let par: u32;
+    unsafe {
+        asm!("mcr p15, 0, $1, c7, c8, 0
+              isb
+              mrc p15, 0, $0, c7, c4, 0" : "=r"(par) : "r"(address) : "memory" : "volatile");
+    }
+    if par & 1 != 0 {
+        None
+    } else if par & (1 << 1) != 0 {
+        // Supersection
+        Some((par & 0xff00_0000) | (address & 0x00ff_ffff))
+    } else {
+        Some((par & !0xfff) | (address & 0xfff))
+    }
+}
+
+/// Interpret TEX[0], C and B of all descriptors as an index into PRRR and NMRR
+pub unsafe fn enable_tex_remap(prrr: u32, nmrr: u32) {
+    asm!("mcr p15, 0, $0, c10, c2, 0
+          mcr p15, 0, $1, c10, c2, 1" :: "r"(prrr), "r"(nmrr) : "memory" : "volatile");
+    write_sctlr(read_sctlr() | SCTLR_TRE);
+    invalidate_tlb();
+}
+
+/// Clean the data cache line containing address to the point of unification
+#[inline]
+pub fn clean_dcache_line(address: u32) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c7, c11, 1
+              dsb" :: "r"(address & !(CACHE_LINE_SIZE - 1)) : "memory" : "volatile");
+    }
+}
+
+pub fn clean_dcache_range(start: u32, size: u32) {
+    let first = start & !(CACHE_LINE_SIZE - 1);
+    for line in (first..start + size).step_by(CACHE_LINE_SIZE as usize) {
+        clean_dcache_line(line);
+    }
+}
+
+/// Invalidate the instruction cache and the branch predictor, e.g. after code was written
+#[inline]
+pub fn invalidate_icache() {
+    unsafe {
+        asm!("mcr p15, 0, $0, c7, c5, 0
+              mcr p15, 0, $0, c7, c5, 6
+              dsb
+              isb" :: "r"(0) : "memory" : "volatile");
+    }
+}
+
+/// Clean and invalidate all data and unified caches up to the level of coherency by set and way.
+/// The bootloader may have left the data cache on, so dirty lines have to be written back.
+unsafe fn clean_invalidate_dcache_all() {
+    let clidr: u32;
+    asm!("mrc p15, 1, $0, c0, c0, 1" : "=r"(clidr) ::: "volatile");
+    let level_of_coherency = (clidr >> 24) & 0b111;
+    for level in 0..level_of_coherency {
+        // 0b000 no cache, 0b001 instruction cache only
+        if (clidr >> (level * 3)) & 0b111 < 0b010 {
+            continue;
+        }
+        let ccsidr: u32;
+        asm!("mcr p15, 2, $1, c0, c0, 0
+              isb
+              mrc p15, 1, $0, c0, c0, 0" : "=r"(ccsidr) : "r"(level << 1) :: "volatile");
+        let line_shift = (ccsidr & 0b111) + 4;
+        let ways = ((ccsidr >> 3) & 0x3ff) + 1;
+        let sets = ((ccsidr >> 13) & 0x7fff) + 1;
+        let way_shift = if ways > 1 { (ways - 1).leading_zeros() } else { 0 };
+        for way in 0..ways {
+            for set in 0..sets {
+                let set_way = (way << way_shift) | (set << line_shift) | (level << 1);
+                asm!("mcr p15, 0, $0, c7, c14, 2" :: "r"(set_way) :: "volatile");
+            }
+        }
+    }
+    asm!("dsb" :::: "volatile");
+}
+
+/// Turn on the data and instruction caches and the branch predictor.
+/// The memory types are taken from the translation tables, so TEX remap has to be set up first.
+pub unsafe fn enable_caches() {
+    clean_invalidate_dcache_all();
+    invalidate_icache();
+    write_sctlr(read_sctlr() | SCTLR_C | SCTLR_I | SCTLR_Z);
+}
+
+pub fn branch_prediction_enabled() -> bool {
+    read_sctlr() & SCTLR_Z != 0
+}
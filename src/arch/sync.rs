@@ -0,0 +1,83 @@
+//! Locks that are safe to use with interrupts
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::ops;
+use spin::{Mutex, MutexGuard};
+
+/// Mask IRQs and return whether they were enabled before
+#[inline]
+fn disable_irq() -> bool {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs $0, cpsr" : "=r"(cpsr) ::: "volatile");
+        asm!("cpsid i" :::: "volatile");
+    }
+    cpsr & (1 << 7) == 0
+}
+
+#[inline]
+fn enable_irq() {
+    unsafe { asm!("cpsie i" :::: "volatile") };
+}
+
+/// A spin lock that masks IRQs while it is held.
+/// An interrupt handler can therefore never spin on a lock held by the code it interrupted.
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(data: T) -> Self {
+        IrqSafeMutex { inner: Mutex::new(data) }
+    }
+
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let irq_enabled = disable_irq();
+        IrqSafeMutexGuard { guard: Some(self.inner.lock()), irq_enabled: irq_enabled }
+    }
+
+    /// Take the lock if it is free, e.g. in an exception handler that may have interrupted the
+    /// holder of the lock
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let irq_enabled = disable_irq();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard { guard: Some(guard), irq_enabled: irq_enabled }),
+            None => {
+                if irq_enabled {
+                    enable_irq();
+                }
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T> {
+    // Option so that the lock is released before IRQs are unmasked again
+    guard: Option<MutexGuard<'a, T>>,
+    irq_enabled: bool,
+}
+
+impl<'a, T> ops::Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> ops::DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> ops::Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        if self.irq_enabled {
+            enable_irq();
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Detection of the installed DRAM
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+use crate::bsp::memory_map;
+
+/// Values saved by init.s before the MMU is set up
+#[derive(Copy,Clone,Debug)]
+pub struct BootParameters {
+    /// Pointer to ATAGs or a device tree, r2 as handed over by U-Boot
+    pub boot_params: u32,
+    /// Content of the EMIF SDRAM_CONFIG register
+    pub sdram_config: u32,
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Source {
+    Atags,
+    Emif,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Atags => f.write_str("ATAGs"),
+            Source::Emif => f.write_str("EMIF configuration"),
+            Source::Default => f.write_str("compile time default"),
+        }
+    }
+}
+
+/// Usable DRAM starting at DRAM_START
+#[derive(Copy,Clone,Debug)]
+pub struct DramInfo {
+    pub size: u32,
+    pub source: Source,
+}
+
+const ATAG_NONE: u32 = 0x0000_0000;
+const ATAG_CORE: u32 = 0x5441_0001;
+const ATAG_MEM: u32 = 0x5441_0002;
+// The device tree magic 0xd00dfeed is stored big endian
+const DTB_MAGIC: u32 = 0xedfe_0dd0;
+
+/// Size of the memory described by the EMIF SDRAM_CONFIG register
+pub fn emif_size(sdram_config: u32) -> Option<u32> {
+    let narrow_mode = (sdram_config >> 14) & 0b11;
+    let row_size = (sdram_config >> 7) & 0b111;
+    let ibank = (sdram_config >> 4) & 0b111;
+    let ebank = (sdram_config >> 3) & 0b1;
+    let page_size = sdram_config & 0b111;
+    // 0 means the EMIF has not been configured by the bootloader
+    if sdram_config == 0 || ibank > 3 || page_size > 3 || narrow_mode > 1 {
+        return None;
+    }
+    let row_bits = 9 + row_size;
+    let column_bits = 8 + page_size;
+    let bank_bits = ibank;
+    let width_bits = 2 - narrow_mode; // 32 or 16 bit bus
+    let bits = row_bits + column_bits + bank_bits + width_bits + ebank;
+    if bits > 30 {
+        return None;
+    }
+    Some(1 << bits)
+}
+
+/// End of the DRAM that is mapped linearly while the boot parameters are read
+const BOOT_MAPPED_END: u32 = memory_map::DRAM_START.as_u32() + memory_map::BOOT_LINEAR_MAP_SIZE;
+
+/// Whether the boot parameters are word aligned and in the part of DRAM mapped by init.s
+fn is_mapped(boot_params: u32) -> bool {
+    boot_params >= memory_map::DRAM_START.as_u32() && boot_params < BOOT_MAPPED_END && boot_params % 4 == 0
+}
+
+/// Pointer to a word of the boot parameters in the kernel linear mapping
+fn linear_pointer(address: u32) -> *const u32 {
+    (address - memory_map::DRAM_START.as_u32() + memory_map::KERNEL_VIRTUAL_START) as *const u32
+}
+
+/// Size of the memory at DRAM_START described by ATAG_MEM entries.
+/// The ATAGs are read through the kernel linear mapping, a list that is not terminated or has
+/// a tag reaching beyond the mapped DRAM is ignored.
+fn atag_size(boot_params: u32) -> Option<u32> {
+    if !is_mapped(boot_params) {
+        return None;
+    }
+    let mut address = boot_params;
+    let mut size = None;
+    // Each tag starts with its size in words and the tag number
+    loop {
+        if BOOT_MAPPED_END - address < 8 {
+            return None;
+        }
+        let tag = linear_pointer(address);
+        let (words, kind) = unsafe { (*tag, *tag.offset(1)) };
+        if address == boot_params && kind != ATAG_CORE {
+            return None;
+        }
+        if words == 0 || kind == ATAG_NONE {
+            return size;
+        }
+        if words < 2 || words > (BOOT_MAPPED_END - address) / 4 {
+            return None;
+        }
+        if kind == ATAG_MEM && words >= 4 && unsafe { *tag.offset(3) } == memory_map::DRAM_START.as_u32() {
+            size = Some(unsafe { *tag.offset(2) });
+        }
+        address += words * 4;
+    }
+}
+
+fn is_device_tree(boot_params: u32) -> bool {
+    is_mapped(boot_params) && unsafe { *linear_pointer(boot_params) } == DTB_MAGIC
+}
+
+/// Find the usable DRAM.
+/// ATAGs from U-Boot are preferred, the EMIF configuration is used otherwise.
+/// The size is limited to what the kernel can map linearly.
+pub fn detect(params: &BootParameters) -> DramInfo {
+    let (size, source) = match (atag_size(params.boot_params), emif_size(params.sdram_config)) {
+        (Some(size), _) => (size, Source::Atags),
+        (None, Some(size)) => (size, Source::Emif),
+        (None, None) => (memory_map::DRAM_SIZE, Source::Default),
+    };
+    DramInfo { size: size.min(memory_map::MAX_DRAM_SIZE), source: source }
+}
+
+pub fn print_info<T: fmt::Write>(serial: &mut T, params: &BootParameters, info: &DramInfo) -> fmt::Result {
+    writeln!(serial, "Board has {} MB RAM (from {})", info.size / (1024 * 1024), info.source)?;
+    if let Some(size) = emif_size(params.sdram_config) {
+        writeln!(serial, "EMIF is configured for {} MB", size / (1024 * 1024))?;
+    }
+    if is_device_tree(params.boot_params) {
+        writeln!(serial, "Boot parameters at {:#x} are a device tree, it is not parsed", params.boot_params)?;
+    }
+    Ok(())
+}
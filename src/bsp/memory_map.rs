@@ -13,6 +13,50 @@ pub const DRAM_SIZE: u32 = (DRAM_END.as_u32() - DRAM_START.as_u32() + 1);
 pub const DRAM_SIZE_KB: u32 = DRAM_SIZE / 1024 ;
 pub const DRAM_SIZE_MB: u32 = DRAM_SIZE_KB / 1024 ;
 
+/// Virtual address of DRAM_START in the kernel linear mapping, __vmem_start in link.ld
+pub const KERNEL_VIRTUAL_START: u32 = 0xC000_0000;
+/// DRAM mapped linearly by init.s, the rest is mapped once the size is detected
+pub const BOOT_LINEAR_MAP_SIZE: u32 = 0x2000_0000;
+/// Largest DRAM the kernel can map linearly, it is followed by the windows of arch::memory::vmem
+pub const MAX_DRAM_SIZE: u32 = 0x3000_0000;
+
+// Windows of kernel virtual addresses above the linear mapping
+/// vmalloc, virtually contiguous memory
+pub const VMALLOC_START: u32 = 0xF000_0000;
+pub const VMALLOC_SIZE: u32 = 0x0800_0000;
+/// Kernel stacks with guard pages
+pub const KERNEL_STACKS_START: u32 = 0xF800_0000;
+pub const KERNEL_STACKS_SIZE: u32 = 0x0100_0000;
+/// ioremap, registers of peripherals
+pub const DEVICE_WINDOW_START: u32 = 0xFE00_0000;
+pub const DEVICE_WINDOW_SIZE: u32 = 0x0100_0000;
+/// Mappings at fixed addresses, the last page is left out so that the end fits into 32 bit
+pub const FIXMAP_START: u32 = 0xFFF0_0000;
+pub const FIXMAP_SIZE: u32 = 0x000F_F000;
+/// The high exception vector address, a fixed slot of the fixmap
+pub const VECTOR_PAGE: u32 = 0xFFFF_0000;
+/// Address of the EMIF SDRAM_CONFIG register, init.s reads it before setting up the MMU
+pub const EMIF_SDRAM_CONFIG: u32 = 0x4C00_0008;
+
+// Layout of the first 64 KB of DRAM in front of the kernel image.
+// These are set up by init.s and are registered as reserved regions.
+// Offsets are relative to DRAM_START.
+
+/// First level translation table written by init.s
+pub const BOOT_TRANSLATION_TABLE_OFFSET: u32 = 0x4000;
+pub const BOOT_TRANSLATION_TABLE_SIZE: u32 = 0x4000;
+/// SVC, IRQ and abort stacks set up by init.s
+pub const BOOT_STACKS_OFFSET: u32 = 0x8000;
+pub const BOOT_STACKS_SIZE: u32 = 0x7000;
+
+/// Uncached memory at the end of DRAM for DMA buffers (EDMA, CPSW)
+pub const DMA_ZONE_SIZE: u32 = 8 * 1024 * 1024;
+
+// Peripherals on the L4 interconnect, each register block is 4 KB
+pub const PERIPHERAL_SIZE: u32 = 0x1000;
+pub const WATCHDOG_BASE: u32 = 0x44E3_5000;
+pub const TIMER0_BASE: u32 = 0x44E0_5000;
+
 
 // For bcm2835:
 // const UART_BASE: u32 = 0x2020_0000;
@@ -0,0 +1,101 @@
+//! Boot time test of the installed DRAM
+// Author: Moritz Doll
+// License: GPLv3
+
+use core::fmt;
+use armv7::PhysicalAddress;
+use crate::arch::memory::{self, PhysicalMemoryMap};
+
+const WORDS_PER_FRAME: u32 = physmem::FRAME_SIZE / 4;
+const INVERSION_PATTERN: u32 = 0x5555_5555;
+
+/// Expected and found value of a failing word
+type Failure = Option<(u32, u32)>;
+
+/// Test all frames that are not used by the kernel with walking ones, address in address and
+/// moving inversions. Frames that fail are reserved and never handed out, once all reserved
+/// region slots are used they are retired without a region.
+/// Runs over the linear mapping, so the frames have to be mapped uncached or the caches must be off.
+/// Returns the number of failures.
+pub fn run<T: fmt::Write>(serial: &mut T, physical_memory: &mut PhysicalMemoryMap) -> Result<usize, fmt::Error> {
+    writeln!(serial, "Testing memory..")?;
+    let mut failures = 0;
+
+    // Data lines: walk a single bit through the first word of each frame
+    failures += pass(serial, physical_memory, false, |address, word| {
+        if address % physmem::FRAME_SIZE != 0 {
+            return None;
+        }
+        (0..32).map(|bit| 1 << bit).find_map(|pattern| unsafe {
+            word.write_volatile(pattern);
+            let found = word.read_volatile();
+            if found != pattern { Some((pattern, found)) } else { None }
+        })
+    })?;
+
+    // Address lines: every word holds its own address, written completely before it is checked
+    failures += pass(serial, physical_memory, false, |address, word| {
+        unsafe { word.write_volatile(address) };
+        None
+    })?;
+    failures += pass(serial, physical_memory, false, |address, word| check(word, address, None))?;
+
+    // Moving inversions: check and invert upwards, then check and restore downwards
+    failures += pass(serial, physical_memory, false, |_, word| {
+        unsafe { word.write_volatile(INVERSION_PATTERN) };
+        None
+    })?;
+    failures += pass(serial, physical_memory, false, |_, word| check(word, INVERSION_PATTERN, Some(!INVERSION_PATTERN)))?;
+    failures += pass(serial, physical_memory, true, |_, word| check(word, !INVERSION_PATTERN, Some(INVERSION_PATTERN)))?;
+    failures += pass(serial, physical_memory, false, |_, word| check(word, INVERSION_PATTERN, None))?;
+
+    writeln!(serial, "Memory test finished with {} failures", failures)?;
+    Ok(failures)
+}
+
+/// Compare a word with expected and write replacement afterwards
+fn check(word: *mut u32, expected: u32, replacement: Option<u32>) -> Failure {
+    let found = unsafe { word.read_volatile() };
+    if let Some(replacement) = replacement {
+        unsafe { word.write_volatile(replacement) };
+    }
+    if found != expected { Some((expected, found)) } else { None }
+}
+
+/// Call test with the physical address and a pointer to every word of the frames that are not
+/// in use. A frame is reserved at its first failing word and skipped by later passes.
+fn pass<T, F>(serial: &mut T, physical_memory: &mut PhysicalMemoryMap, descending: bool, mut test: F) -> Result<usize, fmt::Error>
+    where T: fmt::Write,
+          F: FnMut(u32, *mut u32) -> Failure
+{
+    let num_frames = physical_memory.num_frames();
+    let mut failures = 0;
+    for i in 0..num_frames {
+        let frame = if descending { num_frames - 1 - i } else { i };
+        if physical_memory.is_used(frame) {
+            continue;
+        }
+        let start = physical_memory.frame_address(frame);
+        let base = memory::linear_address(start) as *mut u32;
+        for j in 0..WORDS_PER_FRAME {
+            let index = if descending { WORDS_PER_FRAME - 1 - j } else { j };
+            let word = unsafe { base.offset(index as isize) };
+            if let Some((expected, found)) = test(start.as_u32() + index * 4, word) {
+                report(serial, physical_memory, start, index * 4, expected, found)?;
+                failures += 1;
+                break;
+            }
+        }
+    }
+    Ok(failures)
+}
+
+fn report<T: fmt::Write>(serial: &mut T, physical_memory: &mut PhysicalMemoryMap, frame: PhysicalAddress, offset: u32, expected: u32, found: u32) -> fmt::Result {
+    writeln!(serial, "Memory error at {:#x}: expected {:#010x}, found {:#010x}", frame + offset, expected, found)?;
+    if physical_memory.reserve_region("bad memory", frame, physmem::FRAME_SIZE).is_err() {
+        if let Err(err) = physical_memory.retire_frame(frame) {
+            writeln!(serial, "Could not retire bad frame {:#x}: {:?}", frame, err)?;
+        }
+    }
+    Ok(())
+}
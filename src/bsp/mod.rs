@@ -3,4 +3,6 @@
 // License: GPLv3
 
 pub mod memory_map;
+pub mod dram;
+pub mod memtest;
 
@@ -1,5 +1,34 @@
 // Author: Moritz Doll
 // License: GPLv3
 
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::arch::memory;
+use crate::bsp::memory_map;
+
 // Beaglebone Black:
 pub use sitara::device::*;
+
+/// Address of the console UART, the physical address is only mapped by the boot translation table
+static CONSOLE_BASE: AtomicU32 = AtomicU32::new(memory_map::UART_BASE.as_u32());
+
+/// The UART used for kernel messages, usable from any context
+pub fn console() -> uart::Uart {
+    uart::Uart::new(CONSOLE_BASE.load(Ordering::Relaxed))
+}
+
+/// Map the console UART with ioremap, has to be called before the boot translation table is left
+pub fn init_console() -> memory::Result<uart::Uart> {
+    let base = memory::ioremap(memory_map::UART_BASE.as_u32(), memory_map::PERIPHERAL_SIZE)?;
+    CONSOLE_BASE.store(base.as_u32(), Ordering::Relaxed);
+    Ok(console())
+}
+
+pub fn init_watchdog() -> memory::Result<watchdog::Watchdog> {
+    let base = memory::ioremap(memory_map::WATCHDOG_BASE, memory_map::PERIPHERAL_SIZE)?;
+    Ok(watchdog::Watchdog::new(base.as_u32()))
+}
+
+pub fn init_timer() -> memory::Result<timer::Timer> {
+    let base = memory::ioremap(memory_map::TIMER0_BASE, memory_map::PERIPHERAL_SIZE)?;
+    Ok(timer::Timer::new(base.as_u32()))
+}
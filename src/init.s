@@ -3,6 +3,7 @@
 .equ MEM_BASE, 0x80000000   // Todo: Should be read from somewhere else
 .equ PAGE_BASE, 0x80004000 
 .equ VMEM_BASE, 0xC0000000  // Todo: This should come from somewhere else
+.equ EMIF_SDRAM_CONFIG, 0x4C000008
 
 
 //.arm
@@ -10,6 +11,11 @@
 .global _start, vectors_start, vectors_end
 _start:
 
+// Save the values needed to detect the DRAM (see bsp::dram), r10 and r11 are not used below
+mov r10, r2                 // ATAGs or device tree from U-Boot
+ldr r11, =EMIF_SDRAM_CONFIG
+ldr r11, [r11]              // read before our translation table is active
+
 mov r0, #0
 //mcr p15, 0, r0, c1, c0, 0   // Write SCTLR
 
@@ -26,6 +32,8 @@ mov r2, #0
 // Unsafety: Do not change r0
 
 // !! Mapping the kernel to identity!!
+// Only needed until we jump to the virtual address of init, the kernel address space built by
+// arch::memory::init_kernel_space does not contain it
 
 ldr r0, =PAGE_BASE
 add r0, r0, #(1024*2*4)     // Physical Kernel space starts at 2GB and each 1M entry is 4 byte long; Todo: Remove explicit 2GB mark
@@ -121,8 +129,9 @@ mov r2, r2
 // Set SVC stack pointer
 // Todo: Do this in a more sophisticated way
 // Problem: We don't want to use involved pagetables here. But we only have to setup the svc stack here.
+// The stacks live at 0x8000 - 0xF000, above the translation table (see bsp::memory_map)
 ldr r1, = VMEM_BASE
-add r1, r1, #0x8000
+add r1, r1, #0xC000
 mov sp,r1
 
 msr cpsr, #0x92 // go to IRQ mode with irq masked
@@ -133,6 +142,9 @@ add r1, r1, #0x1000
 mov sp, r1
 msr cpsr, #0x13 // go to SVC mode with irq enabled
 
-bl init
+mov r0, r10
+mov r1, r11
+ldr r2, =init           // continue at the virtual address
+blx r2
 b .             // loop if we return
 
@@ -28,6 +28,30 @@ pub fn kernel_start() -> VirtualAddress {
     unsafe { VirtualAddress::from_ptr(&__vmem_start) }
 }
 
+/// Start of .text, the read-only part of the kernel consists of .text and .rodata
+pub fn ro_start() -> VirtualAddress {
+    extern "C" {
+        static __ro_start: u8;
+    }
+    unsafe { VirtualAddress::from_ptr(&__ro_start) }
+}
+
+/// End of .text and start of .rodata, page aligned
+pub fn text_end() -> VirtualAddress {
+    extern "C" {
+        static __text_end: u8;
+    }
+    unsafe { VirtualAddress::from_ptr(&__text_end) }
+}
+
+/// End of .rodata, page aligned
+pub fn ro_end() -> VirtualAddress {
+    extern "C" {
+        static __ro_end: u8;
+    }
+    unsafe { VirtualAddress::from_ptr(&__ro_end) }
+}
+
 pub fn kernel_memory_size() -> isize {
     extern "C" {
         static __vmem_start: u8;
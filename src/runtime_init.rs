@@ -1,5 +1,5 @@
 #[no_mangle]
-pub unsafe extern "C" fn init() -> ! {
+pub unsafe extern "C" fn init(boot_params: u32, sdram_config: u32) -> ! {
     extern "C" {
         // Boundaries of the .bss section, provided by the linker script
         static mut __bss_start: u32;
@@ -9,6 +9,6 @@ pub unsafe extern "C" fn init() -> ! {
     // Zero out the .bss section
     r0::zero_bss(&mut __bss_start, &mut __bss_end);
 
-    crate::kernel_main()
+    crate::kernel_main(crate::bsp::dram::BootParameters { boot_params: boot_params, sdram_config: sdram_config })
 }
 global_asm!(include_str!("init.s"));